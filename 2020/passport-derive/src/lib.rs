@@ -0,0 +1,366 @@
+//! Derives for the passport fields of day 04.
+//!
+//! The generated code names `PassportField`, `Parsed`, `FieldError`, `ParsePassport`,
+//! `ParseOptions`, `ParseError`, `parse_pairs`, `PassportRules` and `ValidationReport`
+//! unqualified, so they must be in scope where the derives are used.
+
+use proc_macro::TokenStream;
+use proc_macro2::{TokenStream as TokenStream2, TokenTree};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitInt, LitStr};
+
+/// Implements `PassportField<'a>` for a newtype around a `Parsed<'a, _>` value, where
+/// `'a` is the only lifetime parameter of the newtype.
+///
+/// The rule comes from a `#[field(key = "...", kind)]` attribute, where `kind` is one of
+/// - `year(min..=max)` for a `Parsed<'a, i16>` within the range,
+/// - `hex_color` for a `Parsed<'a, &'a str>` such as `#a97842`,
+/// - `digits(n)` for a `Parsed<'a, &'a str>` of exactly `n` digits.
+#[proc_macro_derive(PassportField, attributes(field))]
+pub fn derive_passport_field(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    passport_field(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+enum Kind {
+    Year(syn::ExprRange),
+    HexColor,
+    Digits(LitInt),
+}
+
+fn passport_field(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let mut key: Option<LitStr> = None;
+    let mut kind = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("field")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("key") {
+                key = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("year") {
+                let content;
+                syn::parenthesized!(content in meta.input);
+                kind = Some(Kind::Year(content.parse()?));
+            } else if meta.path.is_ident("hex_color") {
+                kind = Some(Kind::HexColor);
+            } else if meta.path.is_ident("digits") {
+                let content;
+                syn::parenthesized!(content in meta.input);
+                kind = Some(Kind::Digits(content.parse()?));
+            } else {
+                return Err(meta.error("expected `key`, `year`, `hex_color` or `digits`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let name = &input.ident;
+    let missing = |what| syn::Error::new_spanned(name, format!("missing {} in #[field(..)]", what));
+    let key = key.ok_or_else(|| missing("`key = \"...\"`"))?;
+    let kind = kind.ok_or_else(|| missing("the kind of field"))?;
+    let lifetime = lifetime(input)?;
+
+    let body = match kind {
+        Kind::Year(range) => {
+            let (min, max) = match (&range.start, &range.limits, &range.end) {
+                (Some(min), syn::RangeLimits::Closed(_), Some(max)) => (min, max),
+                _ => return Err(syn::Error::new_spanned(range, "expected `min..=max`")),
+            };
+
+            quote! {
+                type Value = Option<i16>;
+
+                fn value(&self) -> Self::Value {
+                    self.0.value()
+                }
+
+                fn parse(raw: &#lifetime str) -> Self {
+                    match raw.parse::<i16>() {
+                        Ok(year) => Self(Parsed::Value(year)),
+                        Err(_) => Self(Parsed::Invalid(raw)),
+                    }
+                }
+
+                fn validate(&self) -> Result<(), FieldError> {
+                    let (value, min, max) = (*self.0.get()?, #min, #max);
+                    if (min..=max).contains(&value) {
+                        Ok(())
+                    } else {
+                        Err(FieldError::OutOfRange { value, min, max, unit: "" })
+                    }
+                }
+            }
+        }
+        Kind::HexColor => string_field(
+            &lifetime,
+            quote! {
+                val.len() == 7
+                    && val.starts_with('#')
+                    && val[1..].chars().all(|c| c.is_ascii_hexdigit())
+            },
+            "#rrggbb".to_owned(),
+        ),
+        Kind::Digits(len) => string_field(
+            &lifetime,
+            quote! {
+                val.len() == #len && val.chars().all(|c| c.is_ascii_digit())
+            },
+            format!("{} digits", len.base10_digits()),
+        ),
+    };
+
+    Ok(quote! {
+        impl<#lifetime> PassportField<#lifetime> for #name<#lifetime> {
+            const KEY: &'static str = #key;
+
+            #body
+        }
+    })
+}
+
+/// The only lifetime parameter of the type, which its fields borrow the input for.
+fn lifetime(input: &DeriveInput) -> syn::Result<syn::Lifetime> {
+    let mut lifetimes = input.generics.lifetimes();
+    match (lifetimes.next(), lifetimes.next()) {
+        (Some(param), None) => Ok(param.lifetime.clone()),
+        _ => Err(syn::Error::new_spanned(
+            &input.generics,
+            "expected exactly one lifetime parameter",
+        )),
+    }
+}
+
+/// The `Parsed<&str>` plumbing, checking the value with `valid`, which sees it as
+/// `val`, and describing the values it accepts as `expected`.
+fn string_field(lifetime: &syn::Lifetime, valid: TokenStream2, expected: String) -> TokenStream2 {
+    quote! {
+        type Value = Option<&#lifetime str>;
+
+        fn value(&self) -> Self::Value {
+            self.0.value()
+        }
+
+        fn parse(raw: &#lifetime str) -> Self {
+            Self(Parsed::Value(raw))
+        }
+
+        fn validate(&self) -> Result<(), FieldError> {
+            let val: &str = self.0.get()?;
+            if #valid {
+                Ok(())
+            } else {
+                Err(FieldError::BadFormat {
+                    raw: val.to_owned(),
+                    expected: #expected,
+                })
+            }
+        }
+    }
+}
+
+/// Implements `ParsePassport<'a>` and `From<&'a str>` for a passport made of fields
+/// borrowing the input for `'a`, giving each `key:value` pair to the field whose
+/// `PassportField::KEY` matches.
+///
+/// A `PassportField` with an empty value, as in `byr:`, is missing and keeps its
+/// `Default`, like an empty value of a `Passport`. A field that is not a `PassportField`
+/// takes its key from `#[passport(key = "...")]` and is built with `From<&'a str>`. The pairs with other keys go to the field marked
+/// `#[passport(extra)]`, if any. The passport must implement `Default`.
+#[proc_macro_derive(PassportKeys, attributes(passport))]
+pub fn derive_passport_keys(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    passport_keys(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn passport_keys(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(input, "expected named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(input, "expected a struct")),
+    };
+
+    let lifetime = lifetime(input)?;
+    let (mut keys, mut arms) = (vec![], vec![]);
+    let mut extra = None;
+    for field in fields {
+        let (ident, ty) = (&field.ident, &field.ty);
+
+        let mut key: Option<LitStr> = None;
+        let mut is_extra = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("passport")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("key") {
+                    key = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("extra") {
+                    is_extra = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `key` or `extra`"))
+                }
+            })?;
+        }
+
+        if is_extra {
+            extra = Some(ident);
+            continue;
+        }
+        let key = match key {
+            Some(key) => {
+                arms.push(quote! {
+                    if key == #key {
+                        passport.#ident = <#ty as From<&#lifetime str>>::from(v)
+                    }
+                });
+                quote!(#key)
+            }
+            None => {
+                arms.push(quote! {
+                    if key == <#ty as PassportField<#lifetime>>::KEY {
+                        passport.#ident = if v.is_empty() {
+                            <#ty as Default>::default()
+                        } else {
+                            <#ty as PassportField<#lifetime>>::parse(v)
+                        }
+                    }
+                });
+                quote!(<#ty as PassportField<#lifetime>>::KEY)
+            }
+        };
+        keys.push(key);
+    }
+
+    let name = &input.ident;
+    let keep_extra = match extra {
+        Some(ident) => quote!(passport.#ident = extra;),
+        None => quote!(let _ = extra;),
+    };
+    Ok(quote! {
+        impl<#lifetime> ParsePassport<#lifetime> for #name<#lifetime> {
+            fn parse_with(raw: &#lifetime str, options: ParseOptions) -> Result<Self, ParseError> {
+                let mut passport = Self::default();
+                let keys = [#(#keys),*];
+                let extra = parse_pairs(raw, options, &keys, |key, v| {
+                    #(#arms)else* else {
+                        unreachable!()
+                    }
+                })?;
+                #keep_extra
+
+                Ok(passport)
+            }
+        }
+
+        impl<#lifetime> From<&#lifetime str> for #name<#lifetime> {
+            fn from(raw: &#lifetime str) -> Self {
+                Self::parse_with(raw, ParseOptions::default())
+                    .expect("lenient parsing never fails")
+            }
+        }
+    })
+}
+
+/// Implements `PassportRules` for a passport from `#[rule(name = "...", check = expr)]`
+/// attributes, each a rule across several fields.
+///
+/// The check is a boolean expression in which every field of the passport it names
+/// stands for the parsed value of that field, as in
+/// `#[rule(name = "age_at_issue", check = issue_year - birth_year >= 16)]`. A rule is
+/// only checked when all of its fields are valid on their own; a failing rule is
+/// reported under its name.
+#[proc_macro_derive(PassportRules, attributes(rule))]
+pub fn derive_passport_rules(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    passport_rules(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn passport_rules(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields: Vec<_> = match &input.data {
+        Data::Struct(data) => data
+            .fields
+            .iter()
+            .filter_map(|f| f.ident.as_ref())
+            .collect(),
+        _ => return Err(syn::Error::new_spanned(input, "expected a struct")),
+    };
+
+    let mut rules = vec![];
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("rule")) {
+        let mut name: Option<LitStr> = None;
+        let mut check: Option<syn::Expr> = None;
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("check") {
+                check = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("expected `name` or `check`"));
+            }
+            Ok(())
+        })?;
+
+        let missing =
+            |what| syn::Error::new_spanned(attr, format!("missing {} in #[rule(..)]", what));
+        let name = name.ok_or_else(|| missing("`name = \"...\"`"))?;
+        let check = check.ok_or_else(|| missing("`check = ...`"))?;
+
+        // the fields the check names, in the order of the struct
+        let mut idents = vec![];
+        collect_idents(quote!(#check), &mut idents);
+        let used: Vec<_> = fields.iter().filter(|f| idents.contains(f)).collect();
+        if used.is_empty() {
+            return Err(syn::Error::new_spanned(check, "the check names no field"));
+        }
+
+        let condition = quote!(#check).to_string();
+        let values = used
+            .iter()
+            .map(|f| format!("{} = {{}}", f))
+            .collect::<Vec<_>>()
+            .join(", ");
+        rules.push(quote! {
+            if #(PassportField::validate(&self.#used).is_ok())&&* {
+                if let (#(Some(#used),)*) = (#(PassportField::value(&self.#used),)*) {
+                    if !(#check) {
+                        report.errors.push((
+                            #name,
+                            FieldError::Violated {
+                                condition: #condition,
+                                values: format!(#values, #(#used),*),
+                            },
+                        ));
+                    }
+                }
+            }
+        });
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics PassportRules for #name #ty_generics #where_clause {
+            fn check_rules(&self, report: &mut ValidationReport) {
+                #(#rules)*
+            }
+        }
+    })
+}
+
+fn collect_idents(tokens: TokenStream2, idents: &mut Vec<syn::Ident>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => idents.push(ident),
+            TokenTree::Group(group) => collect_idents(group.stream(), idents),
+            _ => {}
+        }
+    }
+}
@@ -1,7 +1,12 @@
 fn main() -> eyre::Result<()> {
     // let numbers = &mut [1721, 979, 366, 299, 675, 1456];
 
-    let mut numbers = read_data()?;
+    // `cargo run --bin 01 -- -` reads the expenses from stdin
+    let mut numbers = match std::env::args().nth(1).as_deref() {
+        Some("-") => read_data(std::io::stdin().lock())?,
+        Some(path) => read_data(open(path)?)?,
+        None => read_data(open("data/01.txt")?)?,
+    };
     numbers.sort();
 
     for (idx_i, i) in numbers.iter().enumerate() {
@@ -27,14 +32,71 @@ fn main() -> eyre::Result<()> {
     Err(eyre::eyre!("No answer found!"))
 }
 
-fn read_data() -> eyre::Result<Vec<i32>> {
-    use std::fs::File;
-    use std::io::{BufRead, Lines};
+fn open(path: &str) -> eyre::Result<std::io::BufReader<std::fs::File>> {
+    use eyre::WrapErr;
 
-    let lines: Lines<_> = std::io::BufReader::new(File::open("data/01.txt")?).lines();
-    let numbers = lines
-        .map(|l| l.unwrap().parse::<i32>().unwrap())
-        .collect::<Vec<i32>>();
+    let file = std::fs::File::open(path).wrap_err_with(|| format!("cannot open {}", path))?;
 
-    Ok(numbers)
+    Ok(std::io::BufReader::new(file))
+}
+
+fn read_data<R: std::io::BufRead>(reader: R) -> eyre::Result<Vec<i32>> {
+    expenses(reader).collect()
+}
+
+/// Lazily parses one expense per line, skipping blank lines and `#` comments.
+///
+/// Unparsable entries are reported with their (1-based) line number and content.
+fn expenses<R: std::io::BufRead>(reader: R) -> impl Iterator<Item = eyre::Result<i32>> {
+    reader.lines().enumerate().filter_map(|(idx, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => return Some(Err(eyre::eyre!("line {}: {}", idx + 1, err))),
+        };
+
+        let entry = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => &line[..],
+        };
+        let entry = entry.trim();
+        if entry.is_empty() {
+            return None;
+        }
+
+        Some(entry.parse::<i32>().map_err(|err| {
+            eyre::eyre!(
+                "line {}: cannot parse {:?} as an expense ({})",
+                idx + 1,
+                line,
+                err
+            )
+        }))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        let data = "# exported expenses\n\
+                    1721\n\
+                    \n\
+                    979 # coffee\n   \n\
+                    366\n";
+
+        assert_eq!(vec![1721, 979, 366], read_data(data.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn reports_unparsable_line() {
+        let data = "1721\n979\nabc\n366\n";
+
+        let err = read_data(data.as_bytes()).unwrap_err();
+        assert_eq!(
+            "line 3: cannot parse \"abc\" as an expense (invalid digit found in string)",
+            err.to_string()
+        );
+    }
 }
@@ -1,3 +1,5 @@
+use aoc_2020::{csv_field, json_string, XorShift};
+
 fn main() -> eyre::Result<()> {
     // let sample: Vec<Record> = ["1-3 a: abcde", "1-3 b: cdefg", "2-9 c: ccccccccc"]
     //     .iter()
@@ -5,47 +7,284 @@ fn main() -> eyre::Result<()> {
     //     .collect();
     // let records = sample;
 
-    let records = read_data()?;
-    let count = records
-        .into_iter()
-        // .filter(|rec| rec.valid_for_sled_rental()) // first part
-        .filter(|rec| rec.valid_for_toboggan()) // second part
-        .count();
+    // `cargo run --bin 02 -- --policy 'count($letter) in 1-$n2 and len in 8-20'`
+    // `cargo run --bin 02 -- --report [text|csv|json]`
+    // `cargo run --bin 02 -- --base 0 --unit grapheme`
+    // `cargo run --bin 02 -- --skip-invalid`
+    // `cargo run --bin 02 -- --infer data/02-labelled.txt`
+    // `cargo run --release --bin 02 -- --bench [records]`
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if let ["--bench", rest @ ..] = &args[..] {
+        let records = rest.first().map_or(Ok(1_000_000), |n| n.parse())?;
+        bench(records);
+
+        return Ok(());
+    }
+
+    let mut indexing = Indexing::default();
+    let mut skip_invalid = false;
+    let mut rest = vec![];
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .ok_or_else(|| eyre::eyre!("missing value for {}", arg))
+        };
+        match arg {
+            "--base" => indexing.base = value()?.parse()?,
+            "--unit" => indexing.unit = value()?.parse()?,
+            "--skip-invalid" => skip_invalid = true,
+            _ => rest.push(arg),
+        }
+    }
+
+    if let ["--infer", path] = rest[..] {
+        return print_inference(path, indexing);
+    }
+
+    let (records, rejected) = read_data(skip_invalid, indexing)?;
+    if !rejected.is_empty() {
+        eprintln!("Skipped {} invalid lines:", rejected.len());
+        for Rejected {
+            line,
+            content,
+            error,
+        } in &rejected
+        {
+            eprintln!("  line {}: {}: {:?}", line, error, content);
+        }
+    }
+
+    match rest[..] {
+        [] => {}
+        ["--policy", expr] => {
+            let expr: Expr = expr.parse()?;
+            let count = count_valid(&records, &expr, skip_invalid)?;
+            println!("There are {} valid records for `{}`", count, expr);
+
+            return Ok(());
+        }
+        ["--report"] => return write_report(&records, ReportFormat::Text),
+        ["--report", format] => return write_report(&records, format.parse()?),
+        _ => {
+            return Err(eyre::eyre!(
+                "usage: 02 [--base 0|1] [--unit byte|char|grapheme] [--skip-invalid] \
+                 [--policy EXPR | --report [text|csv|json] | --infer FILE | --bench [records]]"
+            ))
+        }
+    }
+
+    // first part
+    let count = count_valid(&records, Policy::range(), skip_invalid)?;
+    println!("There are {} valid records", count);
+
+    // second part
+    let count = count_valid(&records, Policy::position(), skip_invalid)?;
     println!("There are {} valid records", count);
 
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 struct Policy {
     n1: usize,
     n2: usize,
-    letter: String,
+    letter: char,
+    indexing: Indexing,
 }
 
 impl Policy {
-    fn accepts_range(&self, password: &String) -> bool {
-        let count = password
-            .chars()
-            .filter(|c| c.to_string() == self.letter)
-            .count();
-        self.n1 <= count && count <= self.n2
+    /// The sled rental policy: `letter` appears between `n1` and `n2` times.
+    const RANGE: &'static str = "count($letter) in $n1-$n2";
+    /// The toboggan policy: `letter` is at exactly one of the positions `n1` and `n2`.
+    const POSITION: &'static str = "at($n1) = $letter xor at($n2) = $letter";
+
+    fn accepts_range(&self, password: &str) -> bool {
+        Self::range().eval_with(self, password)
     }
 
-    fn accepts_position(&self, password: &String) -> bool {
-        let match_1 = password
-            .chars()
-            .nth(self.n1 - 1)
-            .map_or(false, |c| c.to_string() == self.letter);
-        let match_2 = password
-            .chars()
-            .nth(self.n2 - 1)
-            .map_or(false, |c| c.to_string() == self.letter);
+    fn accepts_position(&self, password: &str) -> bool {
+        Self::position().eval_with(self, password)
+    }
 
-        match_1 ^ match_2
+    fn range() -> &'static Expr {
+        static EXPR: std::sync::OnceLock<Expr> = std::sync::OnceLock::new();
+        EXPR.get_or_init(|| Self::predefined(Self::RANGE))
+    }
+
+    fn position() -> &'static Expr {
+        static EXPR: std::sync::OnceLock<Expr> = std::sync::OnceLock::new();
+        EXPR.get_or_init(|| Self::predefined(Self::POSITION))
+    }
+
+    fn predefined(src: &str) -> Expr {
+        src.parse()
+            .expect("predefined policies are valid expressions")
+    }
+}
+
+/// How the positions of a policy address the password.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Indexing {
+    base: Base,
+    unit: Unit,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Base {
+    Zero,
+    #[default]
+    One,
+}
+
+impl Base {
+    fn offset(self) -> usize {
+        match self {
+            Base::Zero => 0,
+            Base::One => 1,
+        }
+    }
+}
+
+impl std::str::FromStr for Base {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Base::Zero),
+            "1" => Ok(Base::One),
+            _ => Err(eyre::eyre!("index base must be 0 or 1, found {:?}", s)),
+        }
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Unit {
+    Byte,
+    #[default]
+    Char,
+    Grapheme,
+}
+
+impl Unit {
+    fn name(self) -> &'static str {
+        match self {
+            Unit::Byte => "byte",
+            Unit::Char => "char",
+            Unit::Grapheme => "grapheme",
+        }
+    }
+
+    /// Length of the password counted in this unit.
+    fn len(self, password: &str) -> usize {
+        use unicode_segmentation::UnicodeSegmentation;
+
+        match self {
+            Unit::Byte => password.len(),
+            Unit::Char => password.chars().count(),
+            Unit::Grapheme => password.graphemes(true).count(),
+        }
+    }
+
+    /// The cell at the 0-based index `idx`, if the password is long enough.
+    ///
+    /// When everything up to `idx` is ASCII the char is read directly, otherwise the
+    /// password is walked char by char.
+    fn at(self, password: &str, idx: usize) -> Option<Cell<'_>> {
+        use unicode_segmentation::UnicodeSegmentation;
+
+        let bytes = password.as_bytes();
+        match self {
+            Unit::Byte => bytes.get(idx).map(|&b| Cell::Byte(b)),
+            Unit::Char => match bytes.get(..=idx) {
+                Some(prefix) if prefix.is_ascii() => Some(Cell::Text(&password[idx..=idx])),
+                _ => password
+                    .char_indices()
+                    .nth(idx)
+                    .map(|(start, c)| Cell::Text(&password[start..start + c.len_utf8()])),
+            },
+            Unit::Grapheme => password.graphemes(true).nth(idx).map(Cell::Text),
+        }
+    }
+}
+
+impl std::str::FromStr for Unit {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "byte" => Ok(Unit::Byte),
+            "char" => Ok(Unit::Char),
+            "grapheme" => Ok(Unit::Grapheme),
+            _ => Err(eyre::eyre!(
+                "position unit must be byte, char or grapheme, found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// What a position of the password holds: a whole char or grapheme cluster, or a
+/// single byte that may be part of a multi-byte char.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cell<'p> {
+    Text(&'p str),
+    Byte(u8),
+}
+
+impl Cell<'_> {
+    /// The cell as a single char; a letter never matches a partial char or a cluster
+    /// of several chars.
+    fn char(self) -> Option<char> {
+        match self {
+            Cell::Text(text) => {
+                let mut chars = text.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => None,
+                }
+            }
+            Cell::Byte(b) if b.is_ascii() => Some(b as char),
+            Cell::Byte(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Cell<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cell::Text(text) => write!(f, "{:?}", text),
+            Cell::Byte(b) if b.is_ascii() => write!(f, "{:?}", *b as char),
+            Cell::Byte(b) => write!(f, "byte 0x{:02x}", b),
+        }
+    }
+}
+
+impl std::fmt::Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{} {}", self.n1, self.n2, self.letter)
+    }
+}
+
+/// Counts the records `expr` accepts. A record where `expr` reads a position outside the
+/// password is an error, or is left out with a warning when `skip_invalid` is set.
+fn count_valid(records: &[NumberedRecord], expr: &Expr, skip_invalid: bool) -> eyre::Result<usize> {
+    let mut count = 0;
+    for (line, record) in records {
+        match expr.check_positions(&record.policy, &record.password) {
+            Ok(()) => count += expr.eval(record) as usize,
+            Err(error) if skip_invalid => eprintln!("Skipped line {}: {}", line, error),
+            Err(error) => return Err(eyre::eyre!("line {}: {}", line, error)),
+        }
+    }
+
+    Ok(count)
+}
+
+/// A record with the 1-based line of the input it was read from.
+type NumberedRecord = (usize, Record);
+
 #[derive(Debug)]
 struct Record {
     policy: Policy,
@@ -62,35 +301,1561 @@ impl Record {
     }
 }
 
-fn read_data() -> eyre::Result<Vec<Record>> {
+/// A password policy expression.
+///
+/// ```text
+/// expr  := expr "or" expr | expr "xor" expr | expr "and" expr | "not" expr | "(" expr ")"
+///        | "count" "(" set ")" "in" num "-" num
+///        | "at" "(" num ")" "=" set
+///        | "len" "in" num "-" num
+/// set   := letter | "[" letters "]" | "$letter"
+///        | "digit" | "lower" | "upper" | "alpha" | "alnum" | "punct" | "space" | "any"
+/// num   := digits | "$n1" | "$n2"
+/// ```
+///
+/// `not` binds tighter than `and`, which binds tighter than `xor`, which binds tighter
+/// than `or`. The `$` variables refer to the `Policy` of the record being checked, and
+/// positions are counted as its `Indexing` says, from 1 and in chars by default.
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Count { set: CharSet, min: Num, max: Num },
+    At { pos: Num, set: CharSet },
+    Len { min: Num, max: Num },
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Xor(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, record: &Record) -> bool {
+        self.eval_with(&record.policy, &record.password)
+    }
+
+    fn eval_with(&self, policy: &Policy, password: &str) -> bool {
+        match self {
+            Expr::Count { set, min, max } => {
+                let count = set.count(password, policy);
+                min.resolve(policy) <= count && count <= max.resolve(policy)
+            }
+            Expr::At { pos, set } => cell_at(password, pos.resolve(policy), policy.indexing)
+                .ok()
+                .and_then(Cell::char)
+                .is_some_and(|c| set.contains(c, policy)),
+            Expr::Len { min, max } => {
+                let len = password.chars().count();
+                min.resolve(policy) <= len && len <= max.resolve(policy)
+            }
+            Expr::Not(e) => !e.eval_with(policy, password),
+            Expr::And(l, r) => l.eval_with(policy, password) && r.eval_with(policy, password),
+            Expr::Or(l, r) => l.eval_with(policy, password) || r.eval_with(policy, password),
+            Expr::Xor(l, r) => l.eval_with(policy, password) ^ r.eval_with(policy, password),
+        }
+    }
+
+    /// Checks that every position read by `at` addresses a cell of the password.
+    fn check_positions(&self, policy: &Policy, password: &str) -> Result<(), PositionError> {
+        match self {
+            Expr::At { pos, .. } => {
+                cell_at(password, pos.resolve(policy), policy.indexing).map(|_| ())
+            }
+            Expr::Count { .. } | Expr::Len { .. } => Ok(()),
+            Expr::Not(e) => e.check_positions(policy, password),
+            Expr::And(l, r) | Expr::Or(l, r) | Expr::Xor(l, r) => {
+                l.check_positions(policy, password)?;
+                r.check_positions(policy, password)
+            }
+        }
+    }
+
+    /// Evaluates like `eval_with`, also describing the checks that decided the verdict.
+    fn explain(&self, policy: &Policy, password: &str) -> Verdict {
+        match self {
+            Expr::Count { set, min, max } => {
+                let count = set.count(password, policy);
+                let (min, max) = (min.resolve(policy), max.resolve(policy));
+                Verdict {
+                    valid: min <= count && count <= max,
+                    reason: format!(
+                        "{} appears {} time{}, allowed {}–{}",
+                        set.describe(policy),
+                        count,
+                        if count == 1 { "" } else { "s" },
+                        min,
+                        max
+                    ),
+                }
+            }
+            Expr::At { pos, set } => {
+                let pos = pos.resolve(policy);
+                match cell_at(password, pos, policy.indexing) {
+                    Err(error) => Verdict {
+                        valid: false,
+                        reason: error.to_string(),
+                    },
+                    Ok(c) if c.char().is_some_and(|c| set.contains(c, policy)) => Verdict {
+                        valid: true,
+                        reason: format!("position {} is {}", pos, c),
+                    },
+                    Ok(c) => Verdict {
+                        valid: false,
+                        reason: format!(
+                            "position {} is {}, expected {}",
+                            pos,
+                            c,
+                            set.describe(policy)
+                        ),
+                    },
+                }
+            }
+            Expr::Len { min, max } => {
+                let len = password.chars().count();
+                let (min, max) = (min.resolve(policy), max.resolve(policy));
+                Verdict {
+                    valid: min <= len && len <= max,
+                    reason: format!("length is {}, allowed {}–{}", len, min, max),
+                }
+            }
+            Expr::Not(e) => {
+                let inner = e.explain(policy, password);
+                Verdict {
+                    valid: !inner.valid,
+                    reason: inner.reason,
+                }
+            }
+            Expr::And(l, r) => {
+                let l = l.explain(policy, password);
+                if !l.valid {
+                    return l;
+                }
+                let r = r.explain(policy, password);
+                if !r.valid {
+                    return r;
+                }
+                Verdict::joined(true, "", l, r)
+            }
+            Expr::Or(l, r) => {
+                let l = l.explain(policy, password);
+                if l.valid {
+                    return l;
+                }
+                let r = r.explain(policy, password);
+                if r.valid {
+                    return r;
+                }
+                Verdict::joined(false, "", l, r)
+            }
+            Expr::Xor(l_expr, r_expr) => {
+                let l = l_expr.explain(policy, password);
+                let r = r_expr.explain(policy, password);
+                if let (Expr::At { pos: p1, .. }, Expr::At { pos: p2, .. }) =
+                    (l_expr.as_ref(), r_expr.as_ref())
+                {
+                    let (p1, p2) = (p1.resolve(policy), p2.resolve(policy));
+                    let reason = match (l.valid, r.valid) {
+                        (true, true) => format!("positions {} and {} both match", p1, p2),
+                        (false, false) => format!("neither position {} nor {} matches", p1, p2),
+                        (true, false) => format!("only position {} matches", p1),
+                        (false, true) => format!("only position {} matches", p2),
+                    };
+                    return Verdict {
+                        valid: l.valid ^ r.valid,
+                        reason,
+                    };
+                }
+                match (l.valid, r.valid) {
+                    (true, true) => Verdict::joined(false, "both ", l, r),
+                    (false, false) => Verdict::joined(false, "neither ", l, r),
+                    (true, false) => Verdict { valid: true, ..l },
+                    (false, true) => Verdict { valid: true, ..r },
+                }
+            }
+        }
+    }
+}
+
+/// The cell at position `pos`, counted as the policy's `indexing` says.
+fn cell_at(password: &str, pos: usize, indexing: Indexing) -> Result<Cell<'_>, PositionError> {
+    let base = indexing.base.offset();
+    let idx = pos
+        .checked_sub(base)
+        .ok_or(PositionError::BeforeBase { pos, base })?;
+    indexing
+        .unit
+        .at(password, idx)
+        .ok_or_else(|| PositionError::PastEnd {
+            pos,
+            len: indexing.unit.len(password),
+            unit: indexing.unit,
+        })
+}
+
+/// A position that does not address a cell of the password.
+#[derive(Debug, PartialEq)]
+enum PositionError {
+    BeforeBase { pos: usize, base: usize },
+    PastEnd { pos: usize, len: usize, unit: Unit },
+}
+
+impl std::fmt::Display for PositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PositionError::BeforeBase { pos, base } => {
+                write!(
+                    f,
+                    "position {} is invalid, positions are {}-based",
+                    pos, base
+                )
+            }
+            PositionError::PastEnd { pos, len, unit } => write!(
+                f,
+                "position {} is past the end of the password ({} {}s)",
+                pos,
+                len,
+                unit.name()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PositionError {}
+
+/// Number of occurrences of `letter` in the password.
+///
+/// An ASCII byte never occurs inside a multi-byte UTF-8 sequence, so ASCII letters are
+/// counted over the raw bytes with a branch-free loop the compiler can vectorize.
+fn count_char(password: &str, letter: char) -> usize {
+    if letter.is_ascii() {
+        let letter = letter as u8;
+        password
+            .as_bytes()
+            .iter()
+            .map(|&b| (b == letter) as usize)
+            .sum()
+    } else {
+        password.chars().filter(|&c| c == letter).count()
+    }
+}
+
+/// Outcome of checking one password, with a human readable reason.
+#[derive(Debug, PartialEq)]
+struct Verdict {
+    valid: bool,
+    reason: String,
+}
+
+impl Verdict {
+    fn joined(valid: bool, prefix: &str, l: Verdict, r: Verdict) -> Self {
+        Verdict {
+            valid,
+            reason: format!("{}{} and {}", prefix, l.reason, r.reason),
+        }
+    }
+}
+
+impl std::str::FromStr for Expr {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(src)?,
+            pos: 0,
+            end: src.chars().count() + 1,
+        };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some((col, token)) => Err(eyre::eyre!("column {}: unexpected {:?}", col, token)),
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Count { set, min, max } => write!(f, "count({}) in {}-{}", set, min, max),
+            Expr::At { pos, set } => write!(f, "at({}) = {}", pos, set),
+            Expr::Len { min, max } => write!(f, "len in {}-{}", min, max),
+            Expr::Not(e) => write!(f, "not ({})", e),
+            Expr::And(l, r) => write!(f, "({} and {})", l, r),
+            Expr::Or(l, r) => write!(f, "({} or {})", l, r),
+            Expr::Xor(l, r) => write!(f, "({} xor {})", l, r),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Num {
+    Lit(usize),
+    N1,
+    N2,
+}
+
+impl Num {
+    fn resolve(self, policy: &Policy) -> usize {
+        match self {
+            Num::Lit(n) => n,
+            Num::N1 => policy.n1,
+            Num::N2 => policy.n2,
+        }
+    }
+}
+
+impl std::fmt::Display for Num {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Num::Lit(n) => write!(f, "{}", n),
+            Num::N1 => write!(f, "$n1"),
+            Num::N2 => write!(f, "$n2"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CharClass {
+    Digit,
+    Lower,
+    Upper,
+    Alpha,
+    Alnum,
+    Punct,
+    Space,
+    Any,
+}
+
+impl CharClass {
+    const ALL: [(&'static str, CharClass); 8] = [
+        ("digit", CharClass::Digit),
+        ("lower", CharClass::Lower),
+        ("upper", CharClass::Upper),
+        ("alpha", CharClass::Alpha),
+        ("alnum", CharClass::Alnum),
+        ("punct", CharClass::Punct),
+        ("space", CharClass::Space),
+        ("any", CharClass::Any),
+    ];
+
+    fn contains(self, c: char) -> bool {
+        match self {
+            CharClass::Digit => c.is_numeric(),
+            CharClass::Lower => c.is_lowercase(),
+            CharClass::Upper => c.is_uppercase(),
+            CharClass::Alpha => c.is_alphabetic(),
+            CharClass::Alnum => c.is_alphanumeric(),
+            CharClass::Punct => c.is_ascii_punctuation(),
+            CharClass::Space => c.is_whitespace(),
+            CharClass::Any => true,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        let (name, _) = Self::ALL.iter().find(|(_, class)| *class == self).unwrap();
+        name
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum CharSet {
+    Letters(Vec<char>),
+    PolicyLetter,
+    Class(CharClass),
+}
+
+impl CharSet {
+    fn contains(&self, c: char, policy: &Policy) -> bool {
+        match self {
+            CharSet::Letters(letters) => letters.contains(&c),
+            CharSet::PolicyLetter => c == policy.letter,
+            CharSet::Class(class) => class.contains(c),
+        }
+    }
+
+    fn count(&self, password: &str, policy: &Policy) -> usize {
+        match self {
+            CharSet::Letters(letters) if letters.len() == 1 => count_char(password, letters[0]),
+            CharSet::PolicyLetter => count_char(password, policy.letter),
+            _ => password
+                .chars()
+                .filter(|&c| self.contains(c, policy))
+                .count(),
+        }
+    }
+
+    fn describe(&self, policy: &Policy) -> String {
+        match self {
+            CharSet::Letters(letters) if letters.len() == 1 => format!("letter {:?}", letters[0]),
+            CharSet::Letters(_) => format!("letters {}", self),
+            CharSet::PolicyLetter => format!("letter {:?}", policy.letter),
+            CharSet::Class(class) => format!("{} characters", class.name()),
+        }
+    }
+}
+
+impl std::fmt::Display for CharSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // only a letter reads back as itself, `5` would be a number and `)` a paren
+            CharSet::Letters(letters) if letters.len() == 1 && letters[0].is_ascii_alphabetic() => {
+                write!(f, "{}", letters[0])
+            }
+            CharSet::Letters(letters) => write!(f, "[{}]", letters.iter().collect::<String>()),
+            CharSet::PolicyLetter => write!(f, "$letter"),
+            CharSet::Class(class) => write!(f, "{}", class.name()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(usize),
+    Var(String),
+    Letters(Vec<char>),
+    LParen,
+    RParen,
+    Dash,
+    Equals,
+}
+
+/// Splits a policy expression into tokens tagged with their 1-based column.
+fn tokenize(src: &str) -> eyre::Result<Vec<(usize, Token)>> {
+    let mut tokens = vec![];
+    let mut chars = src.chars().enumerate().peekable();
+
+    while let Some((idx, c)) = chars.next() {
+        let col = idx + 1;
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '-' => Token::Dash,
+            '=' => Token::Equals,
+            '[' => {
+                let mut letters = vec![];
+                loop {
+                    match chars.next() {
+                        Some((_, ']')) => break,
+                        Some((_, c)) => letters.push(c),
+                        None => return Err(eyre::eyre!("column {}: unclosed `[`", col)),
+                    }
+                }
+                if letters.is_empty() {
+                    return Err(eyre::eyre!("column {}: empty letter set", col));
+                }
+                Token::Letters(letters)
+            }
+            c if c.is_ascii_digit() => {
+                let mut digits = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    digits.push(c);
+                }
+                let n = digits
+                    .parse()
+                    .map_err(|err| eyre::eyre!("column {}: {} ({})", col, digits, err))?;
+                Token::Number(n)
+            }
+            '$' => {
+                let mut word = String::new();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric()) {
+                    word.push(c);
+                }
+                Token::Var(word)
+            }
+            c if c.is_alphabetic() => {
+                let mut word = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric()) {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            c => return Err(eyre::eyre!("column {}: unexpected character {:?}", col, c)),
+        };
+        tokens.push((col, token));
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the output of `tokenize`.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Column reported for errors at the end of the input.
+    end: usize,
+}
+
+impl Parser {
+    fn or(&mut self) -> eyre::Result<Expr> {
+        let mut expr = self.xor()?;
+        while self.eat_word("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.xor()?));
+        }
+        Ok(expr)
+    }
+
+    fn xor(&mut self) -> eyre::Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat_word("xor") {
+            expr = Expr::Xor(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> eyre::Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.eat_word("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> eyre::Result<Expr> {
+        if self.eat_word("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+
+        let (col, token) = self.next()?;
+        match token {
+            Token::LParen => {
+                let expr = self.or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Token::Word(w) if w == "count" => {
+                self.expect(Token::LParen)?;
+                let set = self.set()?;
+                self.expect(Token::RParen)?;
+                self.expect_word("in")?;
+                let (min, max) = self.range()?;
+                Ok(Expr::Count { set, min, max })
+            }
+            Token::Word(w) if w == "at" => {
+                self.expect(Token::LParen)?;
+                let pos = self.num()?;
+                self.expect(Token::RParen)?;
+                self.expect(Token::Equals)?;
+                let set = self.set()?;
+                Ok(Expr::At { pos, set })
+            }
+            Token::Word(w) if w == "len" => {
+                self.expect_word("in")?;
+                let (min, max) = self.range()?;
+                Ok(Expr::Len { min, max })
+            }
+            token => Err(eyre::eyre!(
+                "column {}: expected a check, found {:?}",
+                col,
+                token
+            )),
+        }
+    }
+
+    fn range(&mut self) -> eyre::Result<(Num, Num)> {
+        let min = self.num()?;
+        self.expect(Token::Dash)?;
+        let max = self.num()?;
+        Ok((min, max))
+    }
+
+    fn num(&mut self) -> eyre::Result<Num> {
+        match self.next()? {
+            (_, Token::Number(n)) => Ok(Num::Lit(n)),
+            (_, Token::Var(v)) if v == "n1" => Ok(Num::N1),
+            (_, Token::Var(v)) if v == "n2" => Ok(Num::N2),
+            (col, token) => Err(eyre::eyre!(
+                "column {}: expected a number, found {:?}",
+                col,
+                token
+            )),
+        }
+    }
+
+    fn set(&mut self) -> eyre::Result<CharSet> {
+        match self.next()? {
+            (_, Token::Letters(letters)) => Ok(CharSet::Letters(letters)),
+            (_, Token::Var(v)) if v == "letter" => Ok(CharSet::PolicyLetter),
+            (_, Token::Word(w)) if w.chars().count() == 1 => {
+                Ok(CharSet::Letters(w.chars().collect()))
+            }
+            (col, Token::Word(w)) => CharClass::ALL
+                .iter()
+                .find(|(name, _)| *name == w)
+                .map(|&(_, class)| CharSet::Class(class))
+                .ok_or_else(|| eyre::eyre!("column {}: unknown character class {:?}", col, w)),
+            (col, token) => Err(eyre::eyre!(
+                "column {}: expected a letter set, found {:?}",
+                col,
+                token
+            )),
+        }
+    }
+
+    fn next(&mut self) -> eyre::Result<(usize, Token)> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| eyre::eyre!("column {}: unexpected end of policy", self.end))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> eyre::Result<()> {
+        match self.next()? {
+            (_, token) if token == expected => Ok(()),
+            (col, token) => Err(eyre::eyre!(
+                "column {}: expected {:?}, found {:?}",
+                col,
+                expected,
+                token
+            )),
+        }
+    }
+
+    fn expect_word(&mut self, expected: &str) -> eyre::Result<()> {
+        self.expect(Token::Word(expected.to_owned()))
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some((_, Token::Word(w))) if w == word => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReportFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ReportFormat::Text),
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(eyre::eyre!("unknown report format {:?}", s)),
+        }
+    }
+}
+
+/// One record of the password audit with its verdict under both policies.
+struct ReportRow<'r> {
+    line: usize,
+    record: &'r Record,
+    sled_rental: Verdict,
+    toboggan: Verdict,
+}
+
+fn report(records: &[NumberedRecord]) -> Vec<ReportRow<'_>> {
+    records
+        .iter()
+        .map(|&(line, ref record)| ReportRow {
+            line,
+            record,
+            sled_rental: Policy::range().explain(&record.policy, &record.password),
+            toboggan: match Policy::position().check_positions(&record.policy, &record.password) {
+                Ok(()) => Policy::position().explain(&record.policy, &record.password),
+                Err(error) => Verdict {
+                    valid: false,
+                    reason: error.to_string(),
+                },
+            },
+        })
+        .collect()
+}
+
+fn write_report(records: &[NumberedRecord], format: ReportFormat) -> eyre::Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    render_report(&report(records), format, &mut out)?;
+
+    Ok(())
+}
+
+fn render_report<W: std::io::Write>(
+    rows: &[ReportRow],
+    format: ReportFormat,
+    out: &mut W,
+) -> std::io::Result<()> {
+    let verdict = |v: &Verdict| if v.valid { "valid" } else { "invalid" };
+
+    match format {
+        ReportFormat::Text => {
+            for row in rows {
+                writeln!(
+                    out,
+                    "line {}: {}: {}",
+                    row.line, row.record.policy, row.record.password
+                )?;
+                writeln!(
+                    out,
+                    "  sled rental: {} ({})",
+                    verdict(&row.sled_rental),
+                    row.sled_rental.reason
+                )?;
+                writeln!(
+                    out,
+                    "  toboggan:    {} ({})",
+                    verdict(&row.toboggan),
+                    row.toboggan.reason
+                )?;
+            }
+        }
+        ReportFormat::Csv => {
+            writeln!(
+                out,
+                "line,policy,password,sled_rental,sled_rental_reason,toboggan,toboggan_reason"
+            )?;
+            for row in rows {
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{}",
+                    row.line,
+                    csv_field(&row.record.policy.to_string()),
+                    csv_field(&row.record.password),
+                    verdict(&row.sled_rental),
+                    csv_field(&row.sled_rental.reason),
+                    verdict(&row.toboggan),
+                    csv_field(&row.toboggan.reason)
+                )?;
+            }
+        }
+        ReportFormat::Json => {
+            writeln!(out, "[")?;
+            for (idx, row) in rows.iter().enumerate() {
+                let separator = if idx + 1 < rows.len() { "," } else { "" };
+                writeln!(
+                    out,
+                    "  {{\"line\": {}, \"policy\": {}, \"password\": {}, \
+                     \"sled_rental\": {{\"valid\": {}, \"reason\": {}}}, \
+                     \"toboggan\": {{\"valid\": {}, \"reason\": {}}}}}{}",
+                    row.line,
+                    json_string(&row.record.policy.to_string()),
+                    json_string(&row.record.password),
+                    row.sled_rental.valid,
+                    json_string(&row.sled_rental.reason),
+                    row.toboggan.valid,
+                    json_string(&row.toboggan.reason),
+                    separator
+                )?;
+            }
+            writeln!(out, "]")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A password with the verdict a legacy system gave it.
+#[derive(Debug)]
+struct Labelled {
+    password: String,
+    accepted: bool,
+}
+
+impl Labelled {
+    /// Parses `accepted: <password>` or `rejected: <password>`.
+    fn parse(line: &str) -> eyre::Result<Self> {
+        let (label, password) = line
+            .split_once(": ")
+            .ok_or_else(|| eyre::eyre!("expected `<label>: <password>`, found {:?}", line))?;
+        let accepted = match label {
+            "accepted" => true,
+            "rejected" => false,
+            _ => return Err(eyre::eyre!("unknown label {:?}", label)),
+        };
+
+        Ok(Labelled {
+            password: password.to_owned(),
+            accepted,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Interpretation {
+    Range,
+    Position,
+}
+
+/// A policy together with the way it is read.
+#[derive(Clone, Debug, PartialEq)]
+struct Candidate {
+    interpretation: Interpretation,
+    policy: Policy,
+}
+
+impl Candidate {
+    fn accepts(&self, password: &str) -> bool {
+        match self.interpretation {
+            Interpretation::Range => self.policy.accepts_range(password),
+            Interpretation::Position => self.policy.accepts_position(password),
+        }
+    }
+
+    fn agrees_with(&self, examples: &[&Labelled]) -> bool {
+        examples
+            .iter()
+            .all(|ex| self.accepts(&ex.password) == ex.accepted)
+    }
+}
+
+impl std::fmt::Display for Candidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self.interpretation {
+            Interpretation::Range => "range",
+            Interpretation::Position => "position",
+        };
+        write!(f, "{} {}", name, self.policy)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Inference {
+    /// Every policy that agrees with all the labels.
+    Candidates(Vec<Candidate>),
+    /// Indices of a set of examples no policy agrees with; dropping any one of them
+    /// makes the rest consistent.
+    Conflict(Vec<usize>),
+}
+
+/// Searches the policies for those consistent with every labelled example.
+///
+/// The letters tried are `a` to `z` plus everything that appears in the passwords,
+/// and numbers go up to one past the longest password, beyond which no check changes.
+fn infer(examples: &[Labelled], indexing: Indexing) -> Inference {
+    let all: Vec<&Labelled> = examples.iter().collect();
+    let space = policy_space(examples, indexing);
+
+    let candidates: Vec<Candidate> = space
+        .iter()
+        .filter(|c| c.agrees_with(&all))
+        .cloned()
+        .collect();
+    if !candidates.is_empty() {
+        return Inference::Candidates(candidates);
+    }
+
+    // Drop every example the conflict does not depend on.
+    let mut conflict: Vec<usize> = (0..examples.len()).collect();
+    let mut idx = 0;
+    while idx < conflict.len() {
+        let without: Vec<&Labelled> = conflict
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != idx)
+            .map(|(_, &ex)| &examples[ex])
+            .collect();
+        if space.iter().any(|c| c.agrees_with(&without)) {
+            idx += 1;
+        } else {
+            conflict.remove(idx);
+        }
+    }
+
+    Inference::Conflict(conflict)
+}
+
+fn policy_space(examples: &[Labelled], indexing: Indexing) -> Vec<Candidate> {
+    let mut letters: Vec<char> = ('a'..='z')
+        .chain(examples.iter().flat_map(|ex| ex.password.chars()))
+        .collect();
+    letters.sort_unstable();
+    letters.dedup();
+
+    let max = examples
+        .iter()
+        .map(|ex| indexing.unit.len(&ex.password))
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    let mut space = vec![];
+    for &letter in &letters {
+        for n1 in 0..=max {
+            for n2 in n1..=max {
+                let policy = Policy {
+                    n1,
+                    n2,
+                    letter,
+                    indexing,
+                };
+                space.push(Candidate {
+                    interpretation: Interpretation::Range,
+                    policy: policy.clone(),
+                });
+                if n1 >= indexing.base.offset() && n1 < n2 {
+                    space.push(Candidate {
+                        interpretation: Interpretation::Position,
+                        policy,
+                    });
+                }
+            }
+        }
+    }
+
+    space
+}
+
+fn print_inference(path: &str, indexing: Indexing) -> eyre::Result<()> {
+    use eyre::WrapErr;
+
+    let examples = std::fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(idx, line)| Labelled::parse(line).wrap_err_with(|| format!("line {}", idx + 1)))
+        .collect::<eyre::Result<Vec<Labelled>>>()?;
+
+    match infer(&examples, indexing) {
+        Inference::Candidates(candidates) => {
+            println!(
+                "{} policies agree with all {} examples:",
+                candidates.len(),
+                examples.len()
+            );
+            for candidate in candidates {
+                println!("  {}", candidate);
+            }
+        }
+        Inference::Conflict(conflict) => {
+            println!("No policy agrees with these {} examples:", conflict.len());
+            for idx in conflict {
+                let ex = &examples[idx];
+                let label = if ex.accepted { "accepted" } else { "rejected" };
+                println!("  {}: {}", label, ex.password);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A line `read_data` could not parse, kept when skipping invalid lines.
+#[derive(Debug)]
+struct Rejected {
+    line: usize,
+    content: String,
+    error: ParseError,
+}
+
+/// The records of the input, and the lines rejected when `skip_invalid` is set.
+fn read_data(
+    skip_invalid: bool,
+    indexing: Indexing,
+) -> eyre::Result<(Vec<NumberedRecord>, Vec<Rejected>)> {
     use std::fs::File;
-    use std::io::{BufRead, Lines};
+    use std::io::BufRead;
+
+    let lines = std::io::BufReader::new(File::open("data/02.txt")?).lines();
+
+    let mut records = vec![];
+    let mut rejected = vec![];
+    for (idx, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_line(&line) {
+            Ok(mut record) => {
+                record.policy.indexing = indexing;
+                records.push((idx + 1, record));
+            }
+            Err(error) if skip_invalid => rejected.push(Rejected {
+                line: idx + 1,
+                content: line,
+                error,
+            }),
+            Err(error) => return Err(eyre::eyre!("line {}: {}: {:?}", idx + 1, error, line)),
+        }
+    }
+
+    Ok((records, rejected))
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseErrorKind {
+    ExpectedNumber,
+    NumberTooLarge,
+    ExpectedDash,
+    ExpectedLetter,
+    ExpectedColon,
+    MissingPassword,
+    UnterminatedQuote,
+    InvalidEscape(char),
+    TrailingInput,
+}
 
-    let lines: Lines<_> = std::io::BufReader::new(File::open("data/02.txt")?).lines();
+/// Why a record line could not be parsed, and the 1-based column where it went wrong.
+#[derive(Debug, PartialEq)]
+struct ParseError {
+    column: usize,
+    kind: ParseErrorKind,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ParseErrorKind::*;
+
+        write!(f, "column {}: ", self.column)?;
+        match self.kind {
+            ExpectedNumber => write!(f, "expected a number"),
+            NumberTooLarge => write!(f, "number too large"),
+            ExpectedDash => write!(f, "expected `-` between the numbers"),
+            ExpectedLetter => write!(f, "expected the policy letter"),
+            ExpectedColon => write!(f, "expected `:` after the letter"),
+            MissingPassword => write!(f, "missing password"),
+            UnterminatedQuote => write!(f, "unterminated quoted password"),
+            InvalidEscape(c) => write!(f, "invalid escape `\\{}`", c),
+            TrailingInput => write!(f, "unexpected input after the quoted password"),
+        }
+    }
+}
 
-    let records = lines.map(|l| parse_line(l.unwrap()).unwrap()).collect();
+impl std::error::Error for ParseError {}
 
-    Ok(records)
+/// Walks a record line char by char, tracking the position for errors.
+struct Cursor<'l> {
+    line: &'l str,
+    /// Byte offset of the next char.
+    pos: usize,
 }
 
-fn parse_line<L: AsRef<str>>(line: L) -> eyre::Result<Record> {
-    // line parts
-    // 0: "1-3"   -> min-max
-    // 1: "a"     -> letter
-    // 2: ""      -> contiguous separator returns an empty string
-    // 3: "abcde" -> password
-    let parts = line
-        .as_ref()
-        .split(|c| c == ' ' || c == ':')
-        .collect::<Vec<&str>>();
+impl<'l> Cursor<'l> {
+    fn peek(&self) -> Option<char> {
+        self.line[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            column: self.line[..self.pos].chars().count() + 1,
+            kind,
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, ParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+        if start == self.pos {
+            return Err(self.error(ParseErrorKind::ExpectedNumber));
+        }
+
+        self.line[start..self.pos].parse().map_err(|_| ParseError {
+            column: self.line[..start].chars().count() + 1,
+            kind: ParseErrorKind::NumberTooLarge,
+        })
+    }
+
+    fn expect(&mut self, expected: char, kind: ParseErrorKind) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.bump();
+                Ok(())
+            }
+            _ => Err(self.error(kind)),
+        }
+    }
+
+    /// An escaped char after a `\`, which has already been consumed. `eof` is the
+    /// error when the line ends right after the backslash.
+    fn escaped(&mut self, eof: ParseErrorKind) -> Result<char, ParseError> {
+        let escape = self.pos - 1;
+        match self.bump() {
+            Some('\\') => Ok('\\'),
+            Some('"') => Ok('"'),
+            Some(' ') => Ok(' '),
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some(c) => Err(ParseError {
+                column: self.line[..escape].chars().count() + 1,
+                kind: ParseErrorKind::InvalidEscape(c),
+            }),
+            None => Err(self.error(eof)),
+        }
+    }
+
+    /// The rest of the line, or the contents of a double quoted string followed
+    /// only by whitespace.
+    fn password(&mut self) -> Result<String, ParseError> {
+        if self.peek() != Some('"') {
+            let password = self.line[self.pos..].trim_end();
+            if password.is_empty() {
+                return Err(self.error(ParseErrorKind::MissingPassword));
+            }
+            self.pos = self.line.len();
+            return Ok(password.to_owned());
+        }
+
+        let open = self.error(ParseErrorKind::UnterminatedQuote);
+        self.bump();
+        let mut password = String::new();
+        loop {
+            match self.bump() {
+                None => return Err(open),
+                Some('"') => break,
+                Some('\\') => password.push(self.escaped(ParseErrorKind::UnterminatedQuote)?),
+                Some(c) => password.push(c),
+            }
+        }
+
+        self.skip_whitespace();
+        if self.peek().is_some() {
+            return Err(self.error(ParseErrorKind::TrailingInput));
+        }
+
+        Ok(password)
+    }
+}
+
+/// Parses `min-max letter: password`.
+///
+/// Whitespace around the tokens is ignored. The letter may be any char, `\` escapes a
+/// space or a backslash. The password is the rest of the line, so it may contain `:` and
+/// spaces; wrap it in double quotes (with `\"` and `\\` escapes) to keep surrounding
+/// whitespace.
+fn parse_line<L: AsRef<str>>(line: L) -> Result<Record, ParseError> {
+    use ParseErrorKind::*;
+
+    let mut cursor = Cursor {
+        line: line.as_ref(),
+        pos: 0,
+    };
+
+    cursor.skip_whitespace();
+    let n1 = cursor.number()?;
+    cursor.skip_whitespace();
+    cursor.expect('-', ExpectedDash)?;
+    cursor.skip_whitespace();
+    let n2 = cursor.number()?;
+
+    cursor.skip_whitespace();
+    let letter = match cursor.bump() {
+        Some('\\') => cursor.escaped(ExpectedLetter)?,
+        Some(c) => c,
+        None => return Err(cursor.error(ExpectedLetter)),
+    };
+    cursor.skip_whitespace();
+    cursor.expect(':', ExpectedColon)?;
+    cursor.skip_whitespace();
+    let password = cursor.password()?;
 
-    let pol = parts[0].split('-').collect::<Vec<&str>>();
     Ok(Record {
-        password: parts[3].to_owned(),
+        password,
         policy: Policy {
-            letter: parts[1].to_owned(),
-            n1: pol[0].parse().unwrap(),
-            n2: pol[1].parse().unwrap(),
+            letter,
+            n1,
+            n2,
+            indexing: Indexing::default(),
         },
     })
 }
+
+/// Times both policies over generated password dumps, one ASCII and one where every
+/// password carries non-ASCII characters.
+fn bench(records: usize) {
+    for (name, alphabet) in [
+        ("ascii", &['a', 'b', 'c', 'd', 'e', 'x', 'y', 'z'][..]),
+        ("unicode", &['a', 'b', 'c', 'é', 'ß', 'ж', '€', 'z'][..]),
+    ] {
+        let dump = generate(records, alphabet, 0x2020_0202);
+        let bytes: usize = dump.iter().map(|r| r.password.len()).sum();
+
+        for (policy, check) in [
+            (
+                "sled rental",
+                Record::valid_for_sled_rental as fn(&Record) -> bool,
+            ),
+            ("toboggan", Record::valid_for_toboggan),
+        ] {
+            let start = std::time::Instant::now();
+            let valid = dump.iter().filter(|r| check(r)).count();
+            let elapsed = start.elapsed().as_secs_f64();
+
+            println!(
+                "{:>7} {:<11}: {} records ({} valid) in {:.1}ms, {:.1}M records/s, {:.1} MB/s",
+                name,
+                policy,
+                records,
+                valid,
+                elapsed * 1e3,
+                records as f64 / elapsed / 1e6,
+                bytes as f64 / elapsed / 1e6
+            );
+        }
+    }
+}
+
+/// Generates `n` random records over `alphabet`, with passwords of 5 to 40 chars.
+fn generate(n: usize, alphabet: &[char], seed: u64) -> Vec<Record> {
+    let mut rng = XorShift::new(seed);
+    let mut next = |bound: usize| rng.below(bound);
+
+    (0..n)
+        .map(|_| {
+            let len = 5 + next(36);
+            let password: String = (0..len).map(|_| alphabet[next(alphabet.len())]).collect();
+            let n1 = 1 + next(len);
+            let n2 = n1 + next(len - n1 + 1);
+            Record {
+                policy: Policy {
+                    n1,
+                    n2,
+                    letter: alphabet[next(alphabet.len())],
+                    indexing: Indexing::default(),
+                },
+                password,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> Vec<Record> {
+        ["1-3 a: abcde", "1-3 b: cdefg", "2-9 c: ccccccccc"]
+            .iter()
+            .map(|l| parse_line(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn example() {
+        let records = sample();
+
+        let valid: Vec<bool> = records.iter().map(Record::valid_for_sled_rental).collect();
+        assert_eq!(vec![true, false, true], valid);
+
+        let valid: Vec<bool> = records.iter().map(Record::valid_for_toboggan).collect();
+        assert_eq!(vec![true, false, false], valid);
+    }
+
+    #[test]
+    fn custom_policies() {
+        let records = sample();
+        let check = |src: &str| -> Vec<bool> {
+            let expr: Expr = src.parse().unwrap();
+            records.iter().map(|rec| expr.eval(rec)).collect()
+        };
+
+        assert_eq!(vec![false, false, true], check("count([cd]) in 3-9"));
+        assert_eq!(
+            vec![false, true, false],
+            check("len in 1-5 and not at(1) = $letter")
+        );
+        assert_eq!(
+            vec![true, true, true],
+            check("count(lower) in $n2-20 or at(9) = c")
+        );
+        assert_eq!(vec![false, false, false], check("count(digit) in 1-1"));
+        assert_eq!(
+            vec![true, false, true],
+            check("at(1) = [ac] and not (len in 1-1 or at(2) = d)")
+        );
+    }
+
+    #[test]
+    fn tolerant_parsing() {
+        let parse = |line| {
+            let Record { policy, password } = parse_line(line).unwrap();
+            (policy.n1, policy.n2, policy.letter, password)
+        };
+
+        assert_eq!(
+            (1, 3, 'a', "abcde".to_owned()),
+            parse("  1 - 3  a :   abcde  ")
+        );
+        assert_eq!((1, 3, 'a', "ab:c de".to_owned()), parse("1-3 a: ab:c de"));
+        assert_eq!((1, 3, ':', "a:b".to_owned()), parse("1-3 :: a:b"));
+        assert_eq!((1, 3, ' ', "a b".to_owned()), parse("1-3 \\ : a b"));
+        assert_eq!(
+            (1, 3, 'a', " a\"b\\ ".to_owned()),
+            parse(r#"1-3 a: " a\"b\\ "  "#)
+        );
+    }
+
+    #[test]
+    fn parse_errors_with_positions() {
+        use ParseErrorKind::*;
+
+        let err = |line| parse_line(line).unwrap_err();
+        let at = |column, kind| ParseError { column, kind };
+
+        assert_eq!(at(1, ExpectedNumber), err("a: abcde"));
+        assert_eq!(at(4, ExpectedNumber), err("1- a: abcde"));
+        assert_eq!(at(3, ExpectedDash), err("1 3 a: abcde"));
+        assert_eq!(at(6, ExpectedColon), err("1-3 ab: abcde"));
+        assert_eq!(at(10, MissingPassword), err("1-3 a:   "));
+        assert_eq!(at(8, UnterminatedQuote), err(r#"1-3 a: "abc"#));
+        assert_eq!(at(10, InvalidEscape('q')), err(r#"1-3 a: "a\qc""#));
+        assert_eq!(at(14, TrailingInput), err(r#"1-3 a: "abc" d"#));
+        assert_eq!(
+            at(1, NumberTooLarge),
+            err("99999999999999999999999-3 a: abc")
+        );
+        assert_eq!(
+            "column 5: expected `-` between the numbers",
+            err("123 a: abc").to_string()
+        );
+    }
+
+    #[test]
+    fn unicode_passwords() {
+        let record = parse_line("2-3 é: aébcéé").unwrap();
+        assert!(record.valid_for_sled_rental());
+        assert!(record.valid_for_toboggan());
+
+        let record = parse_line("1-2 a: éa").unwrap();
+        assert!(record.valid_for_sled_rental());
+        assert!(record.valid_for_toboggan());
+        let at = |password, pos| {
+            cell_at(password, pos, Indexing::default())
+                .ok()
+                .and_then(Cell::char)
+        };
+        assert_eq!(Some('a'), at("éa", 2));
+        assert_eq!(Some('é'), at("aé", 2));
+        assert_eq!(None, at("aé", 3));
+        assert_eq!(None, at("aé", 0));
+    }
+
+    #[test]
+    fn indexing() {
+        let indexing = |base, unit| Indexing { base, unit };
+        let record = |line: &str, indexing| {
+            let mut record = parse_line(line).unwrap();
+            record.policy.indexing = indexing;
+            record
+        };
+        let toboggan = |line: &str, indexing| {
+            let record = record(line, indexing);
+            Policy::position()
+                .check_positions(&record.policy, &record.password)
+                .map(|()| record.valid_for_toboggan())
+        };
+
+        assert!(toboggan("0-2 a: abcde", indexing(Base::Zero, Unit::Char)).unwrap());
+        assert!(!toboggan("1-3 a: abcde", indexing(Base::Zero, Unit::Char)).unwrap());
+
+        // "é" is two bytes, then "e" followed by a combining acute accent
+        let password = "éae\u{301}";
+        let line = |n1, n2, letter| format!("{}-{} {}: {}", n1, n2, letter, password);
+        assert!(toboggan(&line(3, 4, 'a'), indexing(Base::One, Unit::Byte)).unwrap());
+        assert!(toboggan(&line(2, 4, 'a'), indexing(Base::One, Unit::Char)).unwrap());
+        assert!(toboggan(&line(2, 3, 'e'), indexing(Base::One, Unit::Char)).unwrap());
+        assert!(!toboggan(&line(2, 3, 'e'), indexing(Base::One, Unit::Grapheme)).unwrap());
+
+        let err = |line: &str, indexing| toboggan(line, indexing).unwrap_err().to_string();
+        assert_eq!(
+            "position 0 is invalid, positions are 1-based",
+            err("0-2 a: abcde", Indexing::default())
+        );
+        assert_eq!(
+            "position 5 is past the end of the password (5 chars)",
+            err("1-5 a: abcde", indexing(Base::Zero, Unit::Char))
+        );
+        assert_eq!(
+            "position 4 is past the end of the password (3 graphemes)",
+            err(&line(1, 4, 'e'), indexing(Base::One, Unit::Grapheme))
+        );
+
+        // the numbers are only positions for the policies reading them as such
+        for line in ["1-5 a: aab", "0-2 a: ab"] {
+            let record = record(line, Indexing::default());
+            assert!(record.valid_for_sled_rental(), "{}", line);
+            assert_eq!(
+                Ok(()),
+                Policy::range().check_positions(&record.policy, &record.password)
+            );
+        }
+        let records = [(1, record("1-5 a: aab", Indexing::default()))];
+        let row = &report(&records)[0];
+        assert!(!row.toboggan.valid);
+        assert_eq!(
+            "position 5 is past the end of the password (3 chars)",
+            row.toboggan.reason
+        );
+    }
+
+    #[test]
+    fn byte_checks_match_char_checks() {
+        let records = generate(2_000, &['a', 'b', 'é', 'ж', 'z'], 42);
+        for record in &records {
+            let Record { policy, password } = record;
+            let count = password.chars().filter(|&c| c == policy.letter).count();
+            let range = policy.n1 <= count && count <= policy.n2;
+            let at = |pos: usize| password.chars().nth(pos - 1) == Some(policy.letter);
+            let position = at(policy.n1) ^ at(policy.n2);
+
+            assert_eq!(range, record.valid_for_sled_rental(), "{:?}", record);
+            assert_eq!(position, record.valid_for_toboggan(), "{:?}", record);
+        }
+    }
+
+    fn labelled(lines: &[&str]) -> Vec<Labelled> {
+        lines.iter().map(|l| Labelled::parse(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn infers_policies() {
+        let examples = labelled(&[
+            "accepted: abcde",
+            "rejected: cdefg",
+            "accepted: xbxx",
+            "rejected: bbb",
+            "accepted: abab",
+            "rejected: xbbx",
+        ]);
+
+        let candidates = match infer(&examples, Indexing::default()) {
+            Inference::Candidates(candidates) => candidates,
+            conflict => panic!("expected candidates, found {:?}", conflict),
+        };
+        let candidates: Vec<String> = candidates.iter().map(Candidate::to_string).collect();
+        assert_eq!(vec!["position 2-3 b"], candidates);
+    }
+
+    #[test]
+    fn infers_byte_positions_past_the_char_count() {
+        // "éé" is four bytes, so the `b` sits at byte 5 but char 3
+        let examples = labelled(&[
+            "accepted: ééb",
+            "rejected: éébb",
+            "rejected: ééa",
+            "rejected: bééa",
+        ]);
+        let indexing = Indexing {
+            base: Base::One,
+            unit: Unit::Byte,
+        };
+
+        let candidates = match infer(&examples, indexing) {
+            Inference::Candidates(candidates) => candidates,
+            conflict => panic!("expected candidates, found {:?}", conflict),
+        };
+        let candidates: Vec<String> = candidates.iter().map(Candidate::to_string).collect();
+        assert_eq!(vec!["position 5-6 b"], candidates);
+    }
+
+    #[test]
+    fn reports_conflicting_examples() {
+        let examples = labelled(&[
+            "accepted: abcde",
+            "rejected: cdefg",
+            "accepted: ccccccccc",
+            "rejected: abcde",
+        ]);
+
+        assert_eq!(
+            Inference::Conflict(vec![0, 3]),
+            infer(&examples, Indexing::default())
+        );
+    }
+
+    #[test]
+    fn failure_reasons() {
+        let records: Vec<NumberedRecord> = sample().into_iter().enumerate().collect();
+        let reasons: Vec<String> = report(&records)
+            .into_iter()
+            .flat_map(|row| vec![row.sled_rental.reason, row.toboggan.reason])
+            .collect();
+
+        assert_eq!(
+            vec![
+                "letter 'a' appears 1 time, allowed 1–3",
+                "only position 1 matches",
+                "letter 'b' appears 0 times, allowed 1–3",
+                "neither position 1 nor 3 matches",
+                "letter 'c' appears 9 times, allowed 2–9",
+                "positions 2 and 9 both match",
+            ],
+            reasons
+        );
+
+        let expr: Expr = "len in 1-5 and count(e) in 1-1".parse().unwrap();
+        let (_, record) = &records[2];
+        let verdict = expr.explain(&record.policy, &record.password);
+        assert!(!verdict.valid);
+        assert_eq!("length is 9, allowed 1–5", verdict.reason);
+    }
+
+    #[test]
+    fn csv_and_json_reports() {
+        // the line in the file, blank and rejected lines included
+        let records = vec![(4, parse_line("1-3 a: abcde").unwrap())];
+        let render = |format| {
+            let mut out = vec![];
+            render_report(&report(&records), format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            "line,policy,password,sled_rental,sled_rental_reason,toboggan,toboggan_reason\n\
+             4,1-3 a,abcde,valid,\"letter 'a' appears 1 time, allowed 1–3\",valid,only position 1 matches\n",
+            render(ReportFormat::Csv)
+        );
+        assert_eq!(
+            "[\n  {\"line\": 4, \"policy\": \"1-3 a\", \"password\": \"abcde\", \
+             \"sled_rental\": {\"valid\": true, \"reason\": \"letter 'a' appears 1 time, allowed 1–3\"}, \
+             \"toboggan\": {\"valid\": true, \"reason\": \"only position 1 matches\"}}\n]\n",
+            render(ReportFormat::Json)
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for src in [
+            Policy::POSITION,
+            "at(1) = [5] and count([)]) in 1-2",
+            "count(c) in 1-$n2",
+        ] {
+            let expr: Expr = src.parse().unwrap();
+            assert_eq!(expr, expr.to_string().parse().unwrap(), "{}", expr);
+        }
+        let expr: Expr = "at(1) = [5] or at(2) = [)] or at(3) = c".parse().unwrap();
+        assert_eq!(
+            "((at(1) = [5] or at(2) = [)]) or at(3) = c)",
+            expr.to_string()
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |src: &str| src.parse::<Expr>().unwrap_err().to_string();
+
+        assert_eq!("column 10: unexpected end of policy", err("len in 1-"));
+        assert_eq!(
+            "column 7: unknown character class \"vowel\"",
+            err("count(vowel) in 1-2")
+        );
+        assert_eq!(
+            "column 17: unexpected Word(\"b\")",
+            err("count(a) in 1-2 b")
+        );
+    }
+}
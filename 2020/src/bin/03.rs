@@ -1,10 +1,143 @@
+use aoc_2020::XorShift;
+
 fn main() -> eyre::Result<()> {
-    let map = std::fs::read_to_string("data/03.txt")?;
-    let map = Map::parse(map);
-    let slope = Vec2(3, 1);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    // `cargo run --bin 03 -- --legend '@=rock ~=ice:1,2 *=snow:1 ^=lift:0,3' --run 3,1`
+    let legend: Legend =
+        take_option(&mut args, "--legend")?.map_or(Ok(Legend::default()), str::parse)?;
+    let health: Option<usize> = take_option(&mut args, "--health")?
+        .map(str::parse)
+        .transpose()?;
+
+    // `cargo run --release --bin 03 -- --generate 31 100000000 | \
+    //      cargo run --release --bin 03 -- --stream '3,1 1,2' -`
+    match args[..] {
+        ["--generate", width, height] => {
+            let out = std::io::BufWriter::new(std::io::stdout().lock());
+            return Ok(generate(
+                out,
+                width.parse()?,
+                height.parse()?,
+                4,
+                0x2020_0303,
+            )?);
+        }
+        ["--stream", slopes, path] => {
+            let slopes = slopes
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<eyre::Result<Vec<Vec2>>>()?;
+            let counts = match path {
+                "-" => count_trees_streaming(std::io::stdin().lock(), &legend, &slopes)?,
+                path => count_trees_streaming(
+                    std::io::BufReader::new(std::fs::File::open(path)?),
+                    &legend,
+                    &slopes,
+                )?,
+            };
+            for (slope, count) in slopes.iter().zip(counts) {
+                println!("{},{}: {} trees", slope.0, slope.1, count);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let map = std::io::BufReader::new(std::fs::File::open("data/03.txt")?);
+    let map = Map::read(map, &legend)?;
+
+    // `cargo run --bin 03 -- --slope -1,3 --traversal supercover`
+    // `cargo run --bin 03 -- --search -10..=10 1..=5`
+    // `cargo run --bin 03 -- --path '0,1 1,0 -1,0 1,1:2' --trees 5`
+    // `cargo run --bin 03 -- --render ppm --tiles fit > slide.ppm`
+    let format: Option<Render> = take_option(&mut args, "--render")?
+        .map(str::parse)
+        .transpose()?;
+    let tiles: Tiles =
+        take_option(&mut args, "--tiles")?.map_or(Ok(Tiles::Count(1)), str::parse)?;
+    let start: Vec2 = take_option(&mut args, "--start")?.map_or(Ok(Vec2(0, 0)), str::parse)?;
+
+    let (slope, traversal) = match args[..] {
+        [] => (Vec2(3, 1), Traversal::Landings),
+        ["--slope", slope] => (slope.parse()?, Traversal::Landings),
+        ["--slope", slope, "--traversal", traversal] => (slope.parse()?, traversal.parse()?),
+        ["--search", dx, dy] => {
+            print_ranking(&rank_slopes(&map, parse_range(dx)?, parse_range(dy)?));
+            return Ok(());
+        }
+        ["--race", sleds] => {
+            let sleds = sleds
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<eyre::Result<Vec<Sled>>>()?;
+            print_race(&map, &race(&map, &sleds));
+            return Ok(());
+        }
+        ["--cycle", slope] => {
+            let slide = Toboggan::default()
+                .slide(&map, slope.parse()?, Traversal::Landings)
+                .starting_at(start)
+                .toroidal();
+            match slide.find_cycle() {
+                Some(cycle) => println!(
+                    "after {} moves, repeats every {} moves with {} trees",
+                    cycle.lead_in, cycle.length, cycle.trees
+                ),
+                None => println!("no cycle"),
+            }
+            return Ok(());
+        }
+        ["--run", slope] => {
+            let mut toboggan = Toboggan::default();
+            toboggan.health = health.unwrap_or(toboggan.health);
+            let run = toboggan.run(&map, slope.parse()?);
+            println!("{:?} after {} moves", run.outcome, run.moves);
+            println!("{} trees, {:?}", run.trees, run.toboggan);
+            return Ok(());
+        }
+        ["--path", moves, "--trees", trees] => {
+            let moves = moves
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<eyre::Result<Vec<Move>>>()?;
+            match Toboggan::default().find_path(&map, &moves, trees.parse()?) {
+                Some(path) => {
+                    if let Some(format) = format {
+                        return render(&map, &path.cells, tiles, format);
+                    }
+                    println!("cost {} over {} cells", path.cost, path.cells.len());
+                    for cell in path.cells {
+                        println!("{},{}", cell.0, cell.1);
+                    }
+                }
+                None => println!("no path to the bottom"),
+            }
+            return Ok(());
+        }
+        _ => {
+            return Err(eyre::eyre!(
+                "usage: 03 [--slope DX,DY [--traversal landings|bresenham|supercover]] \
+                 [--search DX_MIN..=DX_MAX DY_MIN..=DY_MAX] \
+                 [--path 'DX,DY[:COST] ...' --trees avoid|PENALTY] \
+                 [--render text|ansi|ppm [--tiles N|fit]] \
+                 [--race 'DX,DY[@X,Y] ...'] [--cycle DX,DY [--start X,Y]] [--run DX,DY [--health N]] [--legend 'C=TERRAIN ...'] \
+                 [--generate WIDTH HEIGHT | --stream 'DX,DY ...' FILE|-]"
+            ))
+        }
+    };
+
+    if let Some(format) = format {
+        let path = Toboggan::default()
+            .slide(&map, slope, traversal)
+            .starting_at(start)
+            .including_start()
+            .path();
+        return render(&map, &path, tiles, format);
+    }
 
     // first part
-    let count = count_trees(&map, slope);
+    let count = count_trees_with(&map, slope, traversal);
     println!("{} trees until the bottom", count);
 
     // second part
@@ -15,111 +148,1319 @@ fn main() -> eyre::Result<()> {
     Ok(())
 }
 
+/// Removes `name` and its value from `args`, wherever they are.
+fn take_option<'a>(args: &mut Vec<&'a str>, name: &str) -> eyre::Result<Option<&'a str>> {
+    match args.iter().position(|&arg| arg == name) {
+        Some(idx) if idx + 1 < args.len() => Ok(args.drain(idx..=idx + 1).nth(1)),
+        Some(_) => Err(eyre::eyre!("{} needs a value", name)),
+        None => Ok(None),
+    }
+}
+
 fn count_trees(map: &Map, slope: Vec2) -> usize {
-    let me = Toboggan {};
+    let me = Toboggan::default();
 
+    trees(me.slide_to_bottom(map, slope))
+}
+
+fn count_trees_with(map: &Map, slope: Vec2, traversal: Traversal) -> usize {
+    let me = Toboggan::default();
+
+    trees(me.slide(map, slope, traversal))
+}
+
+fn trees(slide: SlideIterator) -> usize {
     let mut count = 0;
-    for pos in me.slide_to_bottom(&map, slope) {
+    for (_, pos) in slide {
         match pos {
-            Position::Open => continue,
             Position::Tree => count += 1,
+            _ => continue,
         }
     }
 
     count
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Vec2(usize, usize);
+/// Slopes ordered from the fewest to the most trees.
+struct Ranking(Vec<(Vec2, usize)>);
+
+impl Ranking {
+    /// All the slopes tied for the fewest trees.
+    fn fewest(&self) -> &[(Vec2, usize)] {
+        let min = self.0.first().map(|&(_, trees)| trees);
+        let end = self.0.iter().take_while(|&&(_, t)| Some(t) == min).count();
+        &self.0[..end]
+    }
+
+    /// All the slopes tied for the most trees.
+    fn most(&self) -> &[(Vec2, usize)] {
+        let max = self.0.last().map(|&(_, trees)| trees);
+        let start = self.0.len()
+            - self
+                .0
+                .iter()
+                .rev()
+                .take_while(|&&(_, t)| Some(t) == max)
+                .count();
+        &self.0[start..]
+    }
+}
+
+/// Counts the trees of every slope within the bounds and ranks them.
+///
+/// Slopes that do not move down never reach the bottom and are skipped. Slopes whose
+/// `dx` differ by a multiple of the map width land on the same cells, so each distinct
+/// move is only counted once.
+fn rank_slopes(
+    map: &Map,
+    dx: std::ops::RangeInclusive<isize>,
+    dy: std::ops::RangeInclusive<isize>,
+) -> Ranking {
+    let mut counted = std::collections::HashMap::new();
+
+    let mut ranking = vec![];
+    for dy in dy.filter(|&dy| dy > 0) {
+        for dx in dx.clone() {
+            let slope = Vec2(dx, dy);
+            let key = (dx.rem_euclid(map.width() as isize), dy);
+            let trees = *counted
+                .entry(key)
+                .or_insert_with(|| map.count_landings(slope));
+            ranking.push((slope, trees));
+        }
+    }
+    ranking.sort_by_key(|&(_, trees)| trees);
+
+    Ranking(ranking)
+}
+
+fn print_ranking(ranking: &Ranking) {
+    let slopes = |list: &[(Vec2, usize)]| {
+        list.iter()
+            .map(|(s, _)| format!("{},{}", s.0, s.1))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    if let (Some((_, min)), Some((_, max))) = (ranking.fewest().first(), ranking.most().first()) {
+        println!("fewest trees ({}): {}", min, slopes(ranking.fewest()));
+        println!("most trees ({}): {}", max, slopes(ranking.most()));
+    }
+
+    // slopes with the same number of trees share a rank
+    let mut rank = 0;
+    let mut prev = None;
+    for (idx, &(slope, trees)) in ranking.0.iter().enumerate() {
+        if prev != Some(trees) {
+            rank = idx + 1;
+            prev = Some(trees);
+        }
+        println!("{:>5}. {:>4},{:<4} {} trees", rank, slope.0, slope.1, trees);
+    }
+}
+
+/// Parses an inclusive range such as `-3..=7`, or a single number.
+fn parse_range(s: &str) -> eyre::Result<std::ops::RangeInclusive<isize>> {
+    match s.split_once("..=") {
+        Some((start, end)) => Ok(start.parse()?..=end.parse()?),
+        None => {
+            let n = s.parse()?;
+            Ok(n..=n)
+        }
+    }
+}
+
+/// A cell coordinate or a move, `x` grows to the right and `y` grows downwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Vec2(isize, isize);
+
+impl std::ops::Add for Vec2 {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Vec2(self.0 + rhs.0, self.1 + rhs.1)
+    }
+}
 
 impl Vec2 {
     fn wrapping_x_add(self, rhs: Self, max: usize) -> Self {
-        let new_x = (self.0 + rhs.0) % max;
+        let new_x = (self.0 + rhs.0).rem_euclid(max as isize);
 
         Vec2(new_x, self.1 + rhs.1)
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+impl std::str::FromStr for Vec2 {
+    type Err = eyre::Report;
+
+    /// Parses `dx,dy`, as in `3,1` or `-1,3`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (x, y) = s
+            .split_once(',')
+            .ok_or_else(|| eyre::eyre!("expected `dx,dy`, found {:?}", s))?;
+
+        Ok(Vec2(x.trim().parse()?, y.trim().parse()?))
+    }
+}
+
+/// Which cells of the way down a slide visits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Traversal {
+    /// Only the cells the toboggan lands on after each move.
+    Landings,
+    /// One cell per step along the major axis of the move, as drawn by Bresenham's
+    /// line algorithm.
+    Bresenham,
+    /// Every cell the straight line between landings passes through; both cells are
+    /// taken when it crosses exactly at a corner.
+    Supercover,
+}
+
+impl Traversal {
+    /// The cells visited by one move of `slope` starting at the origin, excluding the
+    /// origin and ending at `slope`.
+    fn steps(self, slope: Vec2) -> Vec<Vec2> {
+        match self {
+            Traversal::Landings => vec![slope],
+            Traversal::Bresenham => bresenham(slope),
+            Traversal::Supercover => supercover(slope),
+        }
+    }
+}
+
+impl std::str::FromStr for Traversal {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "landings" => Ok(Traversal::Landings),
+            "bresenham" => Ok(Traversal::Bresenham),
+            "supercover" => Ok(Traversal::Supercover),
+            _ => Err(eyre::eyre!("unknown traversal {:?}", s)),
+        }
+    }
+}
+
+fn bresenham(to: Vec2) -> Vec<Vec2> {
+    // a slope that goes nowhere still takes a step, like the landings
+    if to == Vec2(0, 0) {
+        return vec![to];
+    }
+
+    let (dx, dy) = (to.0.abs(), -to.1.abs());
+    let (sx, sy) = (to.0.signum(), to.1.signum());
+
+    let mut cells = vec![];
+    let (mut x, mut y) = (0, 0);
+    let mut err = dx + dy;
+    while Vec2(x, y) != to {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+        cells.push(Vec2(x, y));
+    }
+
+    cells
+}
+
+fn supercover(to: Vec2) -> Vec<Vec2> {
+    if to == Vec2(0, 0) {
+        return vec![to];
+    }
+
+    let (dx, dy) = (to.0.abs(), to.1.abs());
+    let (sx, sy) = (to.0.signum(), to.1.signum());
+
+    // Walk along the major axis; `cell` builds a cell from a (major, minor) pair so
+    // both orientations share the same loop.
+    type Cell = fn(isize, isize, Vec2) -> Vec2;
+    let (major, minor, cell): (isize, isize, Cell) = if dx >= dy {
+        (dx, dy, |a, b, s| Vec2(a * s.0, b * s.1))
+    } else {
+        (dy, dx, |a, b, s| Vec2(b * s.0, a * s.1))
+    };
+    let signs = Vec2(sx, sy);
+
+    let mut cells = vec![];
+    let (mut a, mut b) = (0, 0);
+    let mut err = major;
+    let mut prev_err = err;
+    for _ in 0..major {
+        a += 1;
+        err += 2 * minor;
+        if err > 2 * major {
+            b += 1;
+            err -= 2 * major;
+            match (err + prev_err).cmp(&(2 * major)) {
+                std::cmp::Ordering::Less => cells.push(cell(a, b - 1, signs)),
+                std::cmp::Ordering::Greater => cells.push(cell(a - 1, b, signs)),
+                std::cmp::Ordering::Equal => {
+                    // keep rows in order so a slide stops at the right cell
+                    let mut corner = [cell(a, b - 1, signs), cell(a - 1, b, signs)];
+                    corner.sort_by_key(|c| c.1.abs());
+                    cells.extend_from_slice(&corner);
+                }
+            }
+        }
+        cells.push(cell(a, b, signs));
+        prev_err = err;
+    }
+
+    cells
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Position {
     Open,
+    /// Costs the toboggan one health.
     Tree,
+    /// Stops the toboggan for good.
+    Rock,
+    /// Changes the slope the toboggan follows.
+    Ice(Vec2),
+    /// Slows the toboggan down by this much.
+    Snow(usize),
+    /// Moves the toboggan by this offset.
+    Lift(Vec2),
+}
+
+impl std::str::FromStr for Position {
+    type Err = eyre::Report;
+
+    /// Parses `open`, `tree`, `rock`, `ice:DX,DY`, `snow:N` or `lift:DX,DY`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "open" => Ok(Position::Open),
+            None if s == "tree" => Ok(Position::Tree),
+            None if s == "rock" => Ok(Position::Rock),
+            Some(("ice", slope)) => Ok(Position::Ice(slope.parse()?)),
+            Some(("snow", slow_down)) => Ok(Position::Snow(slow_down.parse()?)),
+            Some(("lift", offset)) => Ok(Position::Lift(offset.parse()?)),
+            _ => Err(eyre::eyre!("unknown terrain {:?}", s)),
+        }
+    }
+}
+
+/// Which terrain each map character stands for.
+#[derive(Debug, Clone, PartialEq)]
+struct Legend(std::collections::HashMap<char, Position>);
+
+impl Default for Legend {
+    fn default() -> Self {
+        Legend(
+            vec![('.', Position::Open), ('#', Position::Tree)]
+                .into_iter()
+                .collect(),
+        )
+    }
+}
+
+impl std::str::FromStr for Legend {
+    type Err = eyre::Report;
+
+    /// Parses entries such as `@=rock ~=ice:1,1 *=snow:1 ^=lift:0,3`, added to the
+    /// default `.` and `#`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut legend = Legend::default();
+        for entry in s.split_whitespace() {
+            let mut chars = entry.chars();
+            match (chars.next(), chars.next()) {
+                (Some(ch), Some('=')) => legend.0.insert(ch, chars.as_str().parse()?),
+                _ => return Err(eyre::eyre!("expected `C=terrain`, found {:?}", entry)),
+            };
+        }
+
+        Ok(legend)
+    }
+}
+
+/// One map row, one bit per cell set for trees.
+#[derive(Debug, Default)]
+struct Row {
+    width: usize,
+    words: Vec<u64>,
+    /// The cells that are neither open nor trees, by column.
+    terrain: Vec<(usize, Position)>,
 }
 
+impl Row {
+    /// Replaces the row with `line`, reusing its allocation.
+    fn parse(&mut self, line: &str, legend: &Legend) -> eyre::Result<()> {
+        let line = line.trim_end();
+        self.width = line.chars().count();
+        self.words.clear();
+        self.words.resize(words_for(self.width), 0);
+        self.terrain.clear();
+        for (x, ch) in line.chars().enumerate() {
+            match legend.0.get(&ch) {
+                Some(Position::Open) => {}
+                Some(Position::Tree) => self.words[x / 64] |= 1 << (x % 64),
+                Some(&terrain) => self.terrain.push((x, terrain)),
+                None => return Err(eyre::eyre!("unexpected {:?} at column {}", ch, x + 1)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_tree(&self, x: usize) -> bool {
+        self.words[x / 64] >> (x % 64) & 1 == 1
+    }
+}
+
+fn words_for(width: usize) -> usize {
+    width.div_ceil(64)
+}
+
+/// Reads a map one row at a time, keeping only the current row in memory.
+struct RowReader<'l, R> {
+    reader: R,
+    legend: &'l Legend,
+    line: String,
+    row: Row,
+    /// The index of the row last read, if any.
+    y: Option<usize>,
+}
+
+impl<'l, R: std::io::BufRead> RowReader<'l, R> {
+    fn new(reader: R, legend: &'l Legend) -> Self {
+        RowReader {
+            reader,
+            legend,
+            line: String::new(),
+            row: Row::default(),
+            y: None,
+        }
+    }
+
+    /// The next row with its index, or `None` at the end of the map.
+    ///
+    /// Every row must be as wide as the first one.
+    fn next_row(&mut self) -> eyre::Result<Option<(usize, &Row)>> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 || self.line.trim_end().is_empty() {
+            return Ok(None);
+        }
+
+        let y = self.y.map_or(0, |y| y + 1);
+        let width = self.row.width;
+        self.row
+            .parse(&self.line, self.legend)
+            .map_err(|err| eyre::eyre!("row {}: {}", y + 1, err))?;
+        if y > 0 && self.row.width != width {
+            return Err(eyre::eyre!(
+                "row {}: expected {} cells, found {}",
+                y + 1,
+                width,
+                self.row.width
+            ));
+        }
+        self.y = Some(y);
+
+        Ok(Some((y, &self.row)))
+    }
+}
+
+/// The whole map, bit-packed like [`Row`] with every row padded to whole words, in a
+/// single allocation.
+///
+/// Terrain other than trees is rare, so it is kept aside by cell.
 #[derive(Debug)]
 struct Map {
-    lines: Vec<Vec<Position>>,
+    width: usize,
+    height: usize,
+    words: Vec<u64>,
+    terrain: std::collections::HashMap<(usize, usize), Position>,
 }
 
 impl Map {
-    fn parse(raw: String) -> Self {
-        let lines: Vec<Vec<Position>> = raw
-            .lines()
-            .map(|line| {
-                line.chars()
-                    .map(|ch| match ch {
-                        '.' => Position::Open,
-                        '#' => Position::Tree,
-                        _ => unreachable!(),
-                    })
-                    .collect()
-            })
-            .collect();
+    fn read<R: std::io::BufRead>(reader: R, legend: &Legend) -> eyre::Result<Self> {
+        let mut rows = RowReader::new(reader, legend);
+        let mut map = Map {
+            width: 0,
+            height: 0,
+            words: vec![],
+            terrain: Default::default(),
+        };
+        while let Some((y, row)) = rows.next_row()? {
+            map.width = row.width;
+            map.height += 1;
+            map.words.extend_from_slice(&row.words);
+            for &(x, terrain) in &row.terrain {
+                map.terrain.insert((x, y), terrain);
+            }
+        }
+        if map.width == 0 {
+            return Err(eyre::eyre!("the map is empty"));
+        }
 
-        Map { lines }
+        Ok(map)
     }
 
     fn height(&self) -> usize {
-        self.lines.len()
+        self.height
     }
 
     fn width(&self) -> usize {
-        self.lines[0].len()
+        self.width
+    }
+
+    fn is_tree(&self, x: usize, y: usize) -> bool {
+        let word = self.words[y * words_for(self.width) + x / 64];
+        word >> (x % 64) & 1 == 1
     }
 
     fn at(&self, coord: Vec2) -> Option<Position> {
-        if coord.1 >= self.height() {
+        if coord.1 < 0 || coord.1 >= self.height() as isize {
             return None;
         }
 
-        Some(self.lines[coord.1][coord.0])
+        let (x, y) = (
+            coord.0.rem_euclid(self.width() as isize) as usize,
+            coord.1 as usize,
+        );
+        if self.is_tree(x, y) {
+            Some(Position::Tree)
+        } else {
+            Some(*self.terrain.get(&(x, y)).unwrap_or(&Position::Open))
+        }
+    }
+
+    /// Trees on the landings of `slope`, which must move down, from the top left.
+    ///
+    /// Same as sliding down with [`Toboggan::slide_to_bottom`], but only tests bits.
+    fn count_landings(&self, slope: Vec2) -> usize {
+        let dx = slope.0.rem_euclid(self.width as isize) as usize;
+        let dy = slope.1 as usize;
+
+        let mut x = 0;
+        let mut count = 0;
+        for y in (dy..self.height).step_by(dy) {
+            x = (x + dx) % self.width;
+            count += self.is_tree(x, y) as usize;
+        }
+
+        count
     }
 }
 
+/// Counts the trees on the landings of every slope while reading the map row by row, so
+/// the map is never held in memory.
+fn count_trees_streaming<R: std::io::BufRead>(
+    reader: R,
+    legend: &Legend,
+    slopes: &[Vec2],
+) -> eyre::Result<Vec<usize>> {
+    if let Some(slope) = slopes.iter().find(|s| s.1 <= 0) {
+        return Err(eyre::eyre!(
+            "slope {},{} never reaches the bottom",
+            slope.0,
+            slope.1
+        ));
+    }
+
+    let mut rows = RowReader::new(reader, legend);
+    let mut xs = vec![0; slopes.len()];
+    let mut counts = vec![0; slopes.len()];
+    while let Some((y, row)) = rows.next_row()? {
+        for ((slope, x), count) in slopes.iter().zip(&mut xs).zip(&mut counts) {
+            if y == 0 || y % slope.1 as usize != 0 {
+                continue;
+            }
+            *x = (*x + slope.0).rem_euclid(row.width as isize);
+            *count += row.is_tree(*x as usize) as usize;
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Writes a random `width` by `height` map where about one cell in `one_in` is a tree.
+fn generate<W: std::io::Write>(
+    mut out: W,
+    width: usize,
+    height: usize,
+    one_in: u64,
+    seed: u64,
+) -> std::io::Result<()> {
+    let mut rng = XorShift::new(seed);
+    let mut line = Vec::with_capacity(width + 1);
+    for _ in 0..height {
+        line.clear();
+        for _ in 0..width {
+            line.push(if rng.next_u64().is_multiple_of(one_in) {
+                b'#'
+            } else {
+                b'.'
+            });
+        }
+        line.push(b'\n');
+        out.write_all(&line)?;
+    }
+
+    Ok(())
+}
+
+/// The cells of a slide with their coordinates.
+///
+/// Coordinates are not wrapped, so a path can be drawn unwrapped; `Map::at` wraps `x`
+/// itself and a toroidal slide wraps `y` as well.
 struct SlideIterator<'m> {
     map: &'m Map,
-    slope: Vec2,
+    /// Where the current move started.
     curr_coord: Vec2,
+    /// Offsets from `curr_coord` of the cells visited during one move.
+    steps: Vec<Vec2>,
+    next_step: usize,
+    /// Moves left before a slope that never changes row has covered its row.
+    moves_left: Option<usize>,
+    /// Whether the start cell is still to be visited.
+    include_start: bool,
+    toroidal: bool,
+}
+
+/// The part of a toroidal slide that repeats forever.
+#[derive(Debug, PartialEq)]
+struct Cycle {
+    /// Moves before the cycle starts.
+    lead_in: usize,
+    /// Moves in one cycle.
+    length: usize,
+    /// Trees visited during one cycle.
+    trees: usize,
+}
+
+impl<'m> SlideIterator<'m> {
+    /// Starts from `start` instead of the top left corner.
+    fn starting_at(mut self, start: Vec2) -> Self {
+        self.curr_coord = start;
+        self
+    }
+
+    /// Visits the start cell too, before the first move.
+    fn including_start(mut self) -> Self {
+        self.include_start = true;
+        self
+    }
+
+    /// Wraps around the top and bottom edges as well, so the slide never ends.
+    fn toroidal(mut self) -> Self {
+        self.toroidal = true;
+        self.moves_left = None;
+        self
+    }
+
+    fn position_at(&self, coord: Vec2) -> Option<Position> {
+        if self.toroidal {
+            self.map.at(Vec2(
+                coord.0,
+                coord.1.rem_euclid(self.map.height() as isize),
+            ))
+        } else {
+            self.map.at(coord)
+        }
+    }
+
+    /// The coordinates of all the cells left on the slide.
+    fn path(self) -> Vec<Vec2> {
+        self.map(|(coord, _)| coord).collect()
+    }
+
+    /// Follows the slide until a move starts on a cell where an earlier one did.
+    ///
+    /// Only a toroidal slide is sure to get there; any other slide returns `None` once
+    /// it leaves the map. The start cell is never counted as part of a cycle.
+    fn find_cycle(mut self) -> Option<Cycle> {
+        let (width, height) = (self.map.width() as isize, self.map.height() as isize);
+        self.include_start = false;
+
+        let mut seen = std::collections::HashMap::new();
+        // trees visited before each move
+        let mut trees_before = vec![0];
+        let mut trees = 0;
+        loop {
+            if self.next_step == 0 {
+                let moves = trees_before.len() - 1;
+                let at = self.curr_coord;
+                let at = Vec2(at.0.rem_euclid(width), at.1.rem_euclid(height));
+                if let Some(&lead_in) = seen.get(&at) {
+                    return Some(Cycle {
+                        lead_in,
+                        length: moves - lead_in,
+                        trees: trees - trees_before[lead_in],
+                    });
+                }
+                seen.insert(at, moves);
+            }
+
+            if let (_, Position::Tree) = self.next()? {
+                trees += 1;
+            }
+            if self.next_step == 0 {
+                trees_before.push(trees);
+            }
+        }
+    }
 }
 
 impl<'m> Iterator for SlideIterator<'m> {
-    type Item = Position;
+    type Item = (Vec2, Position);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let new_coord = self.curr_coord.wrapping_x_add(self.slope, self.map.width());
-        self.curr_coord = new_coord;
-        self.map.at(new_coord)
+        if self.include_start {
+            self.include_start = false;
+            return Some((self.curr_coord, self.position_at(self.curr_coord)?));
+        }
+
+        if self.next_step == 0 {
+            if let Some(left) = self.moves_left.as_mut() {
+                *left = left.checked_sub(1)?;
+            }
+        }
+
+        let new_coord = self.curr_coord + self.steps[self.next_step];
+        self.next_step += 1;
+        if self.next_step == self.steps.len() {
+            self.curr_coord = new_coord;
+            self.next_step = 0;
+        }
+
+        Some((new_coord, self.position_at(new_coord)?))
     }
 }
 
-struct Toboggan {}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Toboggan {
+    /// How many times the slope the toboggan moves at once.
+    speed: usize,
+    health: usize,
+}
+
+impl Default for Toboggan {
+    fn default() -> Self {
+        Toboggan {
+            speed: 1,
+            health: 3,
+        }
+    }
+}
 
 impl Toboggan {
     fn slide_to_bottom<'m>(&self, map: &'m Map, slope: Vec2) -> SlideIterator<'m> {
+        self.slide(map, slope, Traversal::Landings)
+    }
+
+    /// Slides from the top left corner, visiting the cells chosen by `traversal`.
+    ///
+    /// The slide ends when it leaves the map at the top or the bottom. A slope that
+    /// stays on its row ends once its path starts repeating.
+    fn slide<'m>(&self, map: &'m Map, slope: Vec2, traversal: Traversal) -> SlideIterator<'m> {
+        let moves_left = if slope.1 == 0 {
+            let width = map.width() as isize;
+            Some((width / gcd(slope.0.rem_euclid(width), width)) as usize)
+        } else {
+            None
+        };
+        let steps = traversal.steps(slope);
+
         SlideIterator {
             map,
-            slope,
             curr_coord: Vec2(0, 0),
+            steps,
+            next_step: 0,
+            moves_left,
+            include_start: false,
+            toroidal: false,
         }
     }
 }
 
+/// A sled of a race, with the slope it follows and where it starts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sled {
+    slope: Vec2,
+    start: Vec2,
+}
+
+impl std::str::FromStr for Sled {
+    type Err = eyre::Report;
+
+    /// Parses `dx,dy` for a sled starting at the top left, or `dx,dy@x,y`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (slope, start) = match s.split_once('@') {
+            Some((slope, start)) => (slope.parse()?, start.parse()?),
+            None => (s.parse()?, Vec2(0, 0)),
+        };
+
+        Ok(Sled { slope, start })
+    }
+}
+
+/// Sleds on the same cell at the same step.
+#[derive(Debug, PartialEq)]
+struct Collision {
+    step: usize,
+    /// The cell, with `x` wrapped onto the map.
+    cell: Vec2,
+    sleds: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct Race {
+    /// Trees hit by each sled.
+    trees: Vec<usize>,
+    collisions: Vec<Collision>,
+    /// Where each sled is at each step, `None` once it has left the map.
+    timeline: Vec<Vec<Option<(Vec2, Position)>>>,
+}
+
+/// Slides all the sleds down at once, each visiting one cell per step, until they have
+/// all left the map.
+///
+/// Sleds going through each other are reported but keep going.
+fn race(map: &Map, sleds: &[Sled]) -> Race {
+    let me = Toboggan::default();
+    let mut slides: Vec<_> = sleds
+        .iter()
+        .map(|sled| Some(me.slide_to_bottom(map, sled.slope).starting_at(sled.start)))
+        .collect();
+
+    let mut trees = vec![0; sleds.len()];
+    let mut collisions = vec![];
+    let mut timeline = vec![];
+    for step in 1.. {
+        let cells: Vec<_> = slides
+            .iter_mut()
+            .map(|slide| {
+                let cell = slide.as_mut()?.next();
+                if cell.is_none() {
+                    *slide = None;
+                }
+                cell
+            })
+            .collect();
+        if cells.iter().all(Option::is_none) {
+            break;
+        }
+
+        let mut occupied = std::collections::BTreeMap::new();
+        for (sled, &(coord, pos)) in cells
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| Some((i, c.as_ref()?)))
+        {
+            if let Position::Tree = pos {
+                trees[sled] += 1;
+            }
+            let cell = (coord.1, coord.0.rem_euclid(map.width() as isize));
+            occupied.entry(cell).or_insert_with(Vec::new).push(sled);
+        }
+        for ((y, x), sleds) in occupied {
+            if sleds.len() > 1 {
+                collisions.push(Collision {
+                    step,
+                    cell: Vec2(x, y),
+                    sleds,
+                });
+            }
+        }
+
+        timeline.push(cells);
+    }
+
+    Race {
+        trees,
+        collisions,
+        timeline,
+    }
+}
+
+fn print_race(map: &Map, race: &Race) {
+    for (sled, trees) in race.trees.iter().enumerate() {
+        println!("sled {}: {} trees", sled, trees);
+    }
+    for collision in &race.collisions {
+        println!(
+            "step {}: sleds {:?} collide at {},{}",
+            collision.step, collision.sleds, collision.cell.0, collision.cell.1
+        );
+    }
+
+    for (idx, cells) in race.timeline.iter().enumerate() {
+        let cells: Vec<String> = cells
+            .iter()
+            .map(|cell| match cell {
+                Some((coord, pos)) => {
+                    let x = coord.0.rem_euclid(map.width() as isize);
+                    let mark = if let Position::Tree = pos { 'X' } else { 'O' };
+                    format!("{:>4},{:<4} {}", x, coord.1, mark)
+                }
+                None => format!("{:>11}", "-"),
+            })
+            .collect();
+        println!("{:>5}. {}", idx + 1, cells.join(" | "));
+    }
+}
+
+/// How a run down the map ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    /// Left the map at the bottom.
+    Finished,
+    /// Left the map at the top.
+    Escaped,
+    /// Hit a rock.
+    Crashed(Vec2),
+    /// Ran out of health on a tree.
+    Wrecked(Vec2),
+    /// Slowed down to a halt in the snow.
+    Stuck(Vec2),
+    /// Came back to a cell in the same state, so it would never end.
+    Looping(Vec2),
+}
+
+#[derive(Debug, PartialEq)]
+struct Run {
+    outcome: Outcome,
+    trees: usize,
+    moves: usize,
+    /// The toboggan as it was at the end of the run.
+    toboggan: Toboggan,
+}
+
+impl Toboggan {
+    /// Rides down from the top left corner, reacting to the terrain of each landing.
+    fn run(mut self, map: &Map, mut slope: Vec2) -> Run {
+        let mut coord = Vec2(0, 0);
+        let (mut trees, mut moves) = (0, 0);
+        let mut seen = std::collections::HashSet::new();
+
+        let outcome = loop {
+            if self.speed == 0 {
+                break Outcome::Stuck(coord);
+            }
+            let wrapped = Vec2(coord.0.rem_euclid(map.width() as isize), coord.1);
+            if !seen.insert((wrapped, slope, self)) {
+                break Outcome::Looping(coord);
+            }
+
+            let speed = self.speed as isize;
+            coord = coord + Vec2(slope.0 * speed, slope.1 * speed);
+            moves += 1;
+            let position = match map.at(coord) {
+                Some(position) => position,
+                None if coord.1 < 0 => break Outcome::Escaped,
+                None => break Outcome::Finished,
+            };
+
+            match position {
+                Position::Open => {}
+                Position::Tree => {
+                    trees += 1;
+                    self.health = self.health.saturating_sub(1);
+                    if self.health == 0 {
+                        break Outcome::Wrecked(coord);
+                    }
+                }
+                Position::Rock => break Outcome::Crashed(coord),
+                Position::Ice(new_slope) => slope = new_slope,
+                Position::Snow(slow_down) => self.speed = self.speed.saturating_sub(slow_down),
+                Position::Lift(offset) => coord = coord + offset,
+            }
+        };
+
+        Run {
+            outcome,
+            trees,
+            moves,
+            toboggan: self,
+        }
+    }
+}
+
+/// A step the pathfinder may take and what it costs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Move {
+    step: Vec2,
+    cost: usize,
+}
+
+impl std::str::FromStr for Move {
+    type Err = eyre::Report;
+
+    /// Parses `dx,dy` for a move costing 1, or `dx,dy:cost`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((step, cost)) => Ok(Move {
+                step: step.parse()?,
+                cost: cost.parse()?,
+            }),
+            None => Ok(Move {
+                step: s.parse()?,
+                cost: 1,
+            }),
+        }
+    }
+}
+
+/// How the pathfinder treats trees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Trees {
+    Avoid,
+    /// Cells with a tree may be entered for this extra cost.
+    Penalty(usize),
+}
+
+impl std::str::FromStr for Trees {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "avoid" => Ok(Trees::Avoid),
+            penalty => Ok(Trees::Penalty(penalty.parse()?)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Path {
+    cells: Vec<Vec2>,
+    cost: usize,
+}
+
+impl Toboggan {
+    /// Finds the cheapest route from any cell of the top row to any cell of the bottom
+    /// row, wrapping horizontally like a slide does.
+    ///
+    /// When every move costs the same and trees are either avoided or free, a plain BFS
+    /// is enough; otherwise it runs A* with the rows left to descend as the heuristic.
+    fn find_path(&self, map: &Map, moves: &[Move], trees: Trees) -> Option<Path> {
+        let (width, height) = (map.width(), map.height());
+        let enter = |cell: Vec2| match (map.at(cell)?, trees) {
+            (Position::Rock, _) | (Position::Tree, Trees::Avoid) => None,
+            (Position::Tree, Trees::Penalty(penalty)) => Some(penalty),
+            // the pathfinder walks, so the other terrain does nothing
+            _ => Some(0),
+        };
+
+        let mut cost = vec![usize::MAX; width * height];
+        let mut prev: Vec<Option<Vec2>> = vec![None; width * height];
+        let idx = |cell: Vec2| cell.1 as usize * width + cell.0 as usize;
+
+        let uniform = moves.windows(2).all(|w| w[0].cost == w[1].cost)
+            && matches!(trees, Trees::Avoid | Trees::Penalty(0));
+
+        let goal = if uniform {
+            let mut queue = std::collections::VecDeque::new();
+            for x in 0..width as isize {
+                let start = Vec2(x, 0);
+                if let Some(c) = enter(start) {
+                    cost[idx(start)] = c;
+                    queue.push_back(start);
+                }
+            }
+
+            let mut goal = None;
+            while let Some(cell) = queue.pop_front() {
+                if cell.1 as usize == height - 1 {
+                    goal = Some(cell);
+                    break;
+                }
+                for m in moves {
+                    let next = cell.wrapping_x_add(m.step, width);
+                    if enter(next).is_some() && cost[idx(next)] == usize::MAX {
+                        cost[idx(next)] = cost[idx(cell)] + m.cost;
+                        prev[idx(next)] = Some(cell);
+                        queue.push_back(next);
+                    }
+                }
+            }
+            goal
+        } else {
+            use std::cmp::Reverse;
+
+            // a lower bound on the cost of the rows left, so A* stays optimal
+            let max_down = moves.iter().map(|m| m.step.1).max().unwrap_or(0);
+            let min_cost = moves.iter().map(|m| m.cost).min().unwrap_or(0);
+            let heuristic = |cell: Vec2| {
+                if max_down <= 0 {
+                    return 0;
+                }
+                let rows_left = (height - 1 - cell.1 as usize) as isize;
+                ((rows_left + max_down - 1) / max_down) as usize * min_cost
+            };
+
+            let mut heap = std::collections::BinaryHeap::new();
+            for x in 0..width as isize {
+                let start = Vec2(x, 0);
+                if let Some(c) = enter(start) {
+                    cost[idx(start)] = c;
+                    heap.push(Reverse((c + heuristic(start), c, x, 0)));
+                }
+            }
+
+            let mut goal = None;
+            while let Some(Reverse((_, c, x, y))) = heap.pop() {
+                let cell = Vec2(x, y);
+                if c > cost[idx(cell)] {
+                    continue;
+                }
+                if y as usize == height - 1 {
+                    goal = Some(cell);
+                    break;
+                }
+                for m in moves {
+                    let next = cell.wrapping_x_add(m.step, width);
+                    if let Some(penalty) = enter(next) {
+                        let next_cost = c + m.cost + penalty;
+                        if next_cost < cost[idx(next)] {
+                            cost[idx(next)] = next_cost;
+                            prev[idx(next)] = Some(cell);
+                            heap.push(Reverse((
+                                next_cost + heuristic(next),
+                                next_cost,
+                                next.0,
+                                next.1,
+                            )));
+                        }
+                    }
+                }
+            }
+            goal
+        }?;
+
+        let mut cells = vec![goal];
+        while let Some(cell) = prev[idx(*cells.last().unwrap())] {
+            cells.push(cell);
+        }
+        cells.reverse();
+
+        Some(Path {
+            cells,
+            cost: cost[idx(goal)],
+        })
+    }
+}
+
+/// How many copies of the map are drawn side by side.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tiles {
+    /// The path wraps around this many copies.
+    Count(usize),
+    /// As many copies as it takes to show the whole path unwrapped.
+    Fit,
+}
+
+impl std::str::FromStr for Tiles {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fit" => Ok(Tiles::Fit),
+            count => match count.parse()? {
+                0 => Err(eyre::eyre!("at least one tile is needed")),
+                count => Ok(Tiles::Count(count)),
+            },
+        }
+    }
+}
+
+/// A cell of the canvas: its terrain, and whether the path goes through it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Cell {
+    terrain: Position,
+    on_path: bool,
+}
+
+impl Cell {
+    /// The character of the cell, in the legend's usual characters. The path is drawn `O`
+    /// over open cells and `X` over trees; over other terrain only the colour shows it.
+    fn char(self) -> char {
+        match (self.terrain, self.on_path) {
+            (Position::Open, false) => '.',
+            (Position::Open, true) => 'O',
+            (Position::Tree, false) => '#',
+            (Position::Tree, true) => 'X',
+            (Position::Rock, _) => '@',
+            (Position::Ice(_), _) => '~',
+            (Position::Snow(_), _) => '*',
+            (Position::Lift(_), _) => '^',
+        }
+    }
+
+    /// The SGR parameters colouring the cell.
+    fn ansi(self) -> &'static str {
+        match (self.terrain, self.on_path) {
+            (Position::Open, false) => "2",
+            (Position::Open, true) => "1;33",
+            (Position::Tree, false) => "32",
+            (Position::Tree, true) => "1;31",
+            (_, true) => "1;30;43",
+            (Position::Rock, false) => "90",
+            (Position::Ice(_), false) => "36",
+            (Position::Snow(_), false) => "97",
+            (Position::Lift(_), false) => "35",
+        }
+    }
+
+    fn rgb(self) -> [u8; 3] {
+        let terrain = match self.terrain {
+            Position::Open if self.on_path => return [230, 180, 0],
+            Position::Tree if self.on_path => return [200, 20, 20],
+            Position::Open => [240, 240, 240],
+            Position::Tree => [34, 110, 34],
+            Position::Rock => [110, 110, 110],
+            Position::Ice(_) => [120, 190, 240],
+            Position::Snow(_) => [255, 255, 255],
+            Position::Lift(_) => [150, 80, 200],
+        };
+        if !self.on_path {
+            return terrain;
+        }
+
+        // halfway to the colour of the path
+        let mut rgb = [230, 180, 0];
+        for (path, terrain) in rgb.iter_mut().zip(terrain) {
+            *path = ((*path as u16 + terrain as u16) / 2) as u8;
+        }
+        rgb
+    }
+}
+
+/// A map with a path drawn over it, ready to be rendered.
+struct Canvas {
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Canvas {
+    fn new(map: &Map, path: &[Vec2], tiles: Tiles) -> Self {
+        let width = map.width() as isize;
+
+        // tiles added on the left so a path going left stays on the canvas
+        let (left, count) = match tiles {
+            Tiles::Count(count) => (0, count as isize),
+            Tiles::Fit => {
+                let min_x = path.iter().map(|c| c.0).min().unwrap_or(0);
+                let max_x = path.iter().map(|c| c.0).max().unwrap_or(0);
+                let left = ((-min_x).max(0) + width - 1) / width;
+                let right = (max_x.max(0) + width) / width;
+                (left, left + right)
+            }
+        };
+
+        let mut rows: Vec<Vec<Cell>> = (0..map.height() as isize)
+            .map(|y| {
+                (0..width * count)
+                    .map(|x| Cell {
+                        terrain: map.at(Vec2(x, y)).expect("the row is on the map"),
+                        on_path: false,
+                    })
+                    .collect()
+            })
+            .collect();
+
+        for coord in path {
+            let x = (coord.0 + left * width).rem_euclid(width * count) as usize;
+            if let Some(row) = rows.get_mut(coord.1 as usize) {
+                row[x].on_path = true;
+            }
+        }
+
+        Canvas { rows }
+    }
+
+    /// Draws the canvas like the puzzle text does, colouring it with ANSI escapes if
+    /// `ansi` is set.
+    fn to_text(&self, ansi: bool) -> String {
+        let mut text = String::new();
+        for row in &self.rows {
+            for cell in row {
+                if ansi {
+                    text.push_str(&format!("\x1b[{}m{}\x1b[0m", cell.ansi(), cell.char()));
+                } else {
+                    text.push(cell.char());
+                }
+            }
+            text.push('\n');
+        }
+
+        text
+    }
+
+    /// Writes a binary PPM image where each cell is a `scale` pixels wide square.
+    fn write_ppm<W: std::io::Write>(&self, mut out: W, scale: usize) -> std::io::Result<()> {
+        let height = self.rows.len();
+        let width = self.rows.first().map_or(0, Vec::len);
+        write!(out, "P6\n{} {}\n255\n", width * scale, height * scale)?;
+
+        for row in &self.rows {
+            let mut line = Vec::with_capacity(width * scale * 3);
+            for cell in row {
+                let rgb = cell.rgb();
+                for _ in 0..scale {
+                    line.extend_from_slice(&rgb);
+                }
+            }
+            for _ in 0..scale {
+                out.write_all(&line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How `--render` draws the map.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Render {
+    Text,
+    Ansi,
+    Ppm,
+}
+
+impl std::str::FromStr for Render {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Render::Text),
+            "ansi" => Ok(Render::Ansi),
+            "ppm" => Ok(Render::Ppm),
+            _ => Err(eyre::eyre!("unknown render format {:?}", s)),
+        }
+    }
+}
+
+fn render(map: &Map, path: &[Vec2], tiles: Tiles, format: Render) -> eyre::Result<()> {
+    let canvas = Canvas::new(map, path, tiles);
+    match format {
+        Render::Text => print!("{}", canvas.to_text(false)),
+        Render::Ansi => print!("{}", canvas.to_text(true)),
+        Render::Ppm => canvas.write_ppm(std::io::stdout().lock(), 4)?,
+    }
+
+    Ok(())
+}
+
+fn gcd(a: isize, b: isize) -> isize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
-    #[test]
-    fn example() {
+    fn example_map() -> Map {
         let map = "..##.......\n\
                         #...#...#..\n\
                         .#....#..#.\n\
@@ -132,7 +1473,336 @@ mod test {
                         #...##....#\n\
                         .#..#...#.#";
 
-        let map = Map::parse(map.to_owned());
+        Map::read(map.as_bytes(), &Legend::default()).unwrap()
+    }
+
+    #[test]
+    fn example() {
+        let map = example_map();
         assert_eq!(7, count_trees(&map, Vec2(3, 1)));
     }
+
+    #[test]
+    fn signed_slopes() {
+        let map = example_map();
+
+        // moving left wraps around the right edge
+        assert_eq!(3, count_trees(&map, Vec2(-3, 1)));
+        // moving up leaves the map right away
+        assert_eq!(0, count_trees(&map, Vec2(1, -1)));
+        // staying on the first row visits each of its cells once
+        assert_eq!(2, count_trees(&map, Vec2(1, 0)));
+        assert_eq!(0, count_trees(&map, Vec2(0, 0)));
+    }
+
+    #[test]
+    fn slope_search() {
+        let map = example_map();
+        let ranking = rank_slopes(&map, -2..=14, 0..=2);
+
+        // dy = 0 is skipped and dx = 14 lands where dx = 3 does
+        assert_eq!(17 * 2, ranking.0.len());
+        for &(slope, trees) in &ranking.0 {
+            assert_eq!(count_trees(&map, slope), trees, "{:?}", slope);
+        }
+
+        assert_eq!(vec![(Vec2(5, 2), 0)], ranking.fewest());
+        assert_eq!(vec![(Vec2(3, 1), 7), (Vec2(14, 1), 7)], ranking.most());
+    }
+
+    fn moves(s: &str) -> Vec<Move> {
+        s.split_whitespace().map(|m| m.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn path_finding() {
+        let map = example_map();
+        let me = Toboggan::default();
+
+        // straight down, only the 8th column has a single tree
+        assert_eq!(None, me.find_path(&map, &moves("0,1"), Trees::Avoid));
+        let path = me
+            .find_path(&map, &moves("0,1"), Trees::Penalty(5))
+            .unwrap();
+        assert_eq!(10 + 5, path.cost);
+        assert!(path.cells.iter().all(|c| c.0 == 7));
+
+        // with sideways moves, BFS finds a tree free route
+        let path = me
+            .find_path(&map, &moves("0,1 1,0 -1,0"), Trees::Avoid)
+            .unwrap();
+        assert_eq!(path.cells.len() - 1, path.cost);
+        for pair in path.cells.windows(2) {
+            let dx = (pair[1].0 - pair[0].0).rem_euclid(11);
+            assert!(
+                [(0, 1), (1, 0), (10, 0)].contains(&(dx, pair[1].1 - pair[0].1)),
+                "{:?}",
+                pair
+            );
+        }
+        assert!(path
+            .cells
+            .iter()
+            .all(|&c| matches!(map.at(c), Some(Position::Open))));
+
+        // expensive sideways moves make Dijkstra prefer a tree
+        let path = me
+            .find_path(&map, &moves("0,1 1,0:10 -1,0:10"), Trees::Penalty(3))
+            .unwrap();
+        assert_eq!(13, path.cost);
+    }
+
+    #[test]
+    fn line_traversals() {
+        let cells = |traversal: Traversal, slope| -> Vec<(isize, isize)> {
+            traversal.steps(slope).iter().map(|v| (v.0, v.1)).collect()
+        };
+
+        assert_eq!(vec![(1, 3)], cells(Traversal::Landings, Vec2(1, 3)));
+        assert_eq!(
+            vec![(0, 1), (1, 2), (1, 3)],
+            cells(Traversal::Bresenham, Vec2(1, 3))
+        );
+        assert_eq!(
+            vec![(0, 1), (1, 1), (0, 2), (1, 2), (1, 3)],
+            cells(Traversal::Supercover, Vec2(1, 3))
+        );
+        assert_eq!(
+            vec![(-1, 0), (-1, 1), (-2, 1)],
+            cells(Traversal::Supercover, Vec2(-2, 1))
+        );
+        assert_eq!(
+            vec![(1, 0), (0, 1), (1, 1), (2, 1), (1, 2), (2, 2)],
+            cells(Traversal::Supercover, Vec2(2, 2))
+        );
+
+        // a zero slope stays put instead of indexing an empty line
+        assert_eq!(vec![(0, 0)], cells(Traversal::Bresenham, Vec2(0, 0)));
+        assert_eq!(vec![(0, 0)], cells(Traversal::Supercover, Vec2(0, 0)));
+
+        let map = example_map();
+        for traversal in [Traversal::Bresenham, Traversal::Supercover] {
+            assert_eq!(0, count_trees_with(&map, Vec2(0, 0), traversal));
+        }
+        assert_eq!(0, count_trees_with(&map, Vec2(1, 3), Traversal::Landings));
+        assert_eq!(7, count_trees_with(&map, Vec2(1, 3), Traversal::Supercover));
+    }
+
+    #[test]
+    fn rendering() {
+        let map = example_map();
+        let path = Toboggan::default().slide_to_bottom(&map, Vec2(3, 1)).path();
+
+        // as drawn in the puzzle text
+        let text = Canvas::new(&map, &path, Tiles::Fit).to_text(false);
+        let mut lines = text.lines();
+        assert_eq!(Some("..##.........##.........##......."), lines.next());
+        assert_eq!(Some("#..O#...#..#...#...#..#...#...#.."), lines.next());
+        assert_eq!(Some(".#....X..#..#....#..#..#....#..#."), lines.next());
+        assert_eq!(7, text.matches('X').count());
+
+        // a single tile wraps the path around
+        let text = Canvas::new(&map, &path, Tiles::Count(1)).to_text(false);
+        assert_eq!(Some("..#.#...#O#"), text.lines().nth(3));
+        assert_eq!(7, text.matches('X').count());
+
+        let mut ppm = vec![];
+        Canvas::new(&map, &path, Tiles::Count(2))
+            .write_ppm(&mut ppm, 2)
+            .unwrap();
+        assert!(ppm.starts_with(b"P6\n44 22\n255\n"));
+        assert_eq!(44 * 22 * 3 + 13, ppm.len());
+
+        // the other terrain keeps its own character and colour, on the path or not
+        let legend: Legend = "%=rock ~=ice:0,1 *=snow:1 ^=lift:0,2".parse().unwrap();
+        let map = Map::read("%~*^\n....\n".as_bytes(), &legend).unwrap();
+        let path = [Vec2(1, 0), Vec2(1, 1)];
+        let canvas = Canvas::new(&map, &path, Tiles::Count(1));
+        assert_eq!("@~*^\n.O..\n", canvas.to_text(false));
+        assert!(canvas
+            .to_text(true)
+            .starts_with("\x1b[90m@\x1b[0m\x1b[1;30;43m~\x1b[0m"));
+        let colours: Vec<[u8; 3]> = canvas.rows[0].iter().map(|cell| cell.rgb()).collect();
+        for (idx, colour) in colours.iter().enumerate() {
+            assert!(!colours[idx + 1..].contains(colour), "{:?}", colours);
+        }
+        let ice = Cell {
+            on_path: false,
+            ..canvas.rows[0][1]
+        };
+        assert_ne!(ice.rgb(), colours[1]);
+    }
+
+    #[test]
+    fn streaming() {
+        let map = example_map();
+        let mut raw = vec![];
+        for y in 0..map.height() {
+            for x in 0..map.width() {
+                raw.push(if map.is_tree(x, y) { b'#' } else { b'.' });
+            }
+            raw.extend_from_slice(b"\r\n");
+        }
+
+        let slopes = [
+            Vec2(1, 1),
+            Vec2(3, 1),
+            Vec2(5, 1),
+            Vec2(7, 1),
+            Vec2(1, 2),
+            Vec2(-3, 1),
+        ];
+        let counts = count_trees_streaming(&raw[..], &Legend::default(), &slopes).unwrap();
+        for (&slope, count) in slopes.iter().zip(counts) {
+            assert_eq!(count_trees(&map, slope), count, "{:?}", slope);
+        }
+
+        // rows wider than a word
+        let mut wide = vec![];
+        generate(&mut wide, 150, 300, 3, 42).unwrap();
+        let map = Map::read(&wide[..], &Legend::default()).unwrap();
+        assert_eq!((150, 300), (map.width(), map.height()));
+        let slopes = [Vec2(3, 1), Vec2(67, 1), Vec2(-130, 3)];
+        let counts = count_trees_streaming(&wide[..], &Legend::default(), &slopes).unwrap();
+        for (&slope, count) in slopes.iter().zip(counts) {
+            assert_eq!(count_trees(&map, slope), count, "{:?}", slope);
+        }
+
+        let err = count_trees_streaming("..#\n.#\n".as_bytes(), &Legend::default(), &[Vec2(1, 1)])
+            .unwrap_err();
+        assert_eq!("row 2: expected 3 cells, found 2", err.to_string());
+        let err = Map::read("..#\n.o.\n".as_bytes(), &Legend::default()).unwrap_err();
+        assert_eq!("row 2: unexpected 'o' at column 2", err.to_string());
+        assert!(
+            count_trees_streaming("..#\n".as_bytes(), &Legend::default(), &[Vec2(1, 0)]).is_err()
+        );
+    }
+
+    #[test]
+    fn terrain() {
+        let legend: Legend = "@=rock ~=ice:0,1 *=snow:1 ^=lift:0,2".parse().unwrap();
+        let run = |map: &str, toboggan: Toboggan, slope| {
+            let map = Map::read(map.as_bytes(), &legend).unwrap();
+            toboggan.run(&map, slope)
+        };
+        let me = Toboggan::default();
+
+        // ice turns the toboggan straight down, into two trees
+        let ride = run("....\n.~..\n.#..\n.#..\n", me, Vec2(1, 1));
+        assert_eq!(Outcome::Finished, ride.outcome);
+        assert_eq!((2, 4, 1), (ride.trees, ride.moves, ride.toboggan.health));
+
+        let ride = run("...\n.@.\n", me, Vec2(1, 1));
+        assert_eq!(Outcome::Crashed(Vec2(1, 1)), ride.outcome);
+
+        let ride = run("...\n.*.\n...\n", me, Vec2(1, 1));
+        assert_eq!(Outcome::Stuck(Vec2(1, 1)), ride.outcome);
+
+        // twice as fast only lands on every other row
+        let fast = Toboggan { speed: 2, ..me };
+        let ride = run("....\n.#..\n..#.\n", fast, Vec2(1, 1));
+        assert_eq!((Outcome::Finished, 1), (ride.outcome, ride.trees));
+
+        // the lift skips the first tree
+        let ride = run("...\n^..\n#..\n...\n#..\n", me, Vec2(0, 1));
+        assert_eq!(
+            (Outcome::Finished, 1, 3),
+            (ride.outcome, ride.trees, ride.moves)
+        );
+
+        let ride = run("...\n", me, Vec2(1, 0));
+        assert_eq!(Outcome::Looping(Vec2(3, 0)), ride.outcome);
+        // each tree costs health, so going round a row with one is not a loop
+        let ride = run("..#\n", me, Vec2(1, 0));
+        assert_eq!(
+            (Outcome::Wrecked(Vec2(8, 0)), 3),
+            (ride.outcome, ride.trees)
+        );
+        let ride = run("...\n", me, Vec2(1, -1));
+        assert_eq!(Outcome::Escaped, ride.outcome);
+
+        // without a legend, the puzzle's slope hits its seven trees
+        let ride = Toboggan { health: 8, ..me }.run(&example_map(), Vec2(3, 1));
+        assert_eq!((Outcome::Finished, 7), (ride.outcome, ride.trees));
+
+        assert!("x=lava".parse::<Legend>().is_err());
+        assert!(Map::read("..x\n".as_bytes(), &legend).is_err());
+    }
+
+    #[test]
+    fn slide_items() {
+        let map = example_map();
+        let me = Toboggan::default();
+
+        let mut slide = me.slide_to_bottom(&map, Vec2(3, 1));
+        assert_eq!(Some((Vec2(3, 1), Position::Open)), slide.next());
+        assert_eq!(Some((Vec2(6, 2), Position::Tree)), slide.next());
+
+        let mut slide = me
+            .slide_to_bottom(&map, Vec2(3, 1))
+            .starting_at(Vec2(2, 0))
+            .including_start();
+        assert_eq!(Some((Vec2(2, 0), Position::Tree)), slide.next());
+        assert_eq!(Some((Vec2(5, 1), Position::Open)), slide.next());
+        assert_eq!(Some((Vec2(32, 10), Position::Tree)), slide.last());
+
+        // wrapping vertically, the puzzle's slope comes back to the start after
+        // going once through every row
+        let mut torus = me.slide_to_bottom(&map, Vec2(3, 1)).toroidal();
+        assert_eq!(Some((Vec2(42, 14), Position::Open)), torus.nth(13));
+        let cycle = me.slide_to_bottom(&map, Vec2(3, 1)).toroidal().find_cycle();
+        assert_eq!(
+            Some(Cycle {
+                lead_in: 0,
+                length: 11,
+                trees: 7
+            }),
+            cycle
+        );
+        let cycle = me
+            .slide(&map, Vec2(1, 0), Traversal::Landings)
+            .starting_at(Vec2(0, 3))
+            .toroidal()
+            .find_cycle();
+        assert_eq!(
+            Some(Cycle {
+                lead_in: 0,
+                length: 11,
+                trees: 4
+            }),
+            cycle
+        );
+
+        assert_eq!(None, me.slide_to_bottom(&map, Vec2(3, 1)).find_cycle());
+    }
+
+    #[test]
+    fn sled_race() {
+        let map = example_map();
+        let sleds: Vec<Sled> = ["3,1", "1,1@2,0", "1,2", "-8,1"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let race = race(&map, &sleds);
+
+        let me = Toboggan::default();
+        for (sled, &trees) in sleds.iter().zip(&race.trees) {
+            let slide = me.slide_to_bottom(&map, sled.slope).starting_at(sled.start);
+            assert_eq!(super::trees(slide), trees, "{:?}", sled);
+        }
+
+        // moving 8 left is moving 3 right, so the first and last sleds stay together
+        let together = |step| Collision {
+            step,
+            cell: Vec2(3 * step as isize % 11, step as isize),
+            sleds: vec![0, 3],
+        };
+        let mut expected: Vec<_> = (1..=10).map(together).collect();
+        expected[0].sleds = vec![0, 1, 3];
+        assert_eq!(expected, race.collisions);
+
+        assert_eq!(10, race.timeline.len());
+        assert_eq!(None, race.timeline[5][2]);
+        assert_eq!(Some((Vec2(4, 2), Position::Open)), race.timeline[1][1]);
+    }
 }
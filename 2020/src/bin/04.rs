@@ -1,43 +1,200 @@
+use aoc_2020::{csv_field, json_string, XorShift};
+use passport_derive::{PassportField, PassportKeys, PassportRules};
+
 fn main() -> eyre::Result<()> {
+    // `cargo run --bin 04 -- --schema rules.txt` checks the passports against other rules
+    // `cargo run --bin 04 -- --keys strict --duplicates error` rejects malformed input
+    // `cargo run --release --bin 04 -- --bench [passports]`
+    // `cargo run --bin 04 -- --stream -` counts the passports read from stdin
+    // `cargo run --bin 04 -- --export json` writes the passports as JSON (or csv, pairs)
+    // `cargo run --bin 04 -- --import csv passports.csv` writes them back as pairs
+    // `cargo run --bin 04 -- --required full` also requires `cid` (or `byr,pid`...) in the
+    // strict checks, and counts the passports having those fields next to part 1
+    // `cargo run --bin 04 -- --compare strict,checked` lists the passports they disagree on
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let required = take_option(&mut args, "--required")?
+        .map(str::parse::<RequiredFields>)
+        .transpose()?;
+    let policy = required.unwrap_or(RequiredFields::NORTH_POLE_CREDENTIALS);
+    let compare = match take_option(&mut args, "--compare")? {
+        Some(pair) => match pair.split_once(',') {
+            Some((a, b)) => (a.parse()?, b.parse()?),
+            None => return Err(eyre::eyre!("expected two strategies, found {:?}", pair)),
+        },
+        None => (Strategy::LENIENT, Strategy::STRICT),
+    };
+    if let ["--bench", rest @ ..] = &args[..] {
+        let passports = rest.first().map_or(Ok(1_000_000), |n| n.parse())?;
+        bench(passports);
+
+        return Ok(());
+    }
+    if let ["--stream", path] = args[..] {
+        let strategies = [Strategy::LENIENT, Strategy::STRICT];
+        let counts = match path {
+            "-" => count_valid_streaming(std::io::stdin().lock(), &strategies, policy)?,
+            path => count_valid_streaming(
+                std::io::BufReader::new(std::fs::File::open(path)?),
+                &strategies,
+                policy,
+            )?,
+        };
+        println!("There are {} valid passports", counts[0]);
+        println!(
+            "There are {} valid passports with strich validation",
+            counts[1]
+        );
+
+        return Ok(());
+    }
+    if let ["--import", format, path] = args[..] {
+        let src = match path {
+            "-" => std::io::read_to_string(std::io::stdin())?,
+            path => std::fs::read_to_string(path)?,
+        };
+        print!("{}", import(&src, format.parse()?)?);
+
+        return Ok(());
+    }
+    let options = ParseOptions {
+        keys: take_option(&mut args, "--keys")?.map_or(Ok(Keys::default()), str::parse)?,
+        duplicates: take_option(&mut args, "--duplicates")?
+            .map_or(Ok(Duplicates::default()), str::parse)?,
+    };
+    if let ["--export", format] = args[..] {
+        let raw = std::fs::read_to_string("data/04.txt")?;
+        let batch: Batch<StrictPassport> = Batch::parse_with(&raw, options)?;
+        batch.write(format.parse()?, &mut std::io::stdout().lock())?;
+
+        return Ok(());
+    }
+    let schema = match args[..] {
+        [] => Schema::parse(STRICT_SCHEMA)?,
+        ["--schema", path] => Schema::parse(&std::fs::read_to_string(path)?)?,
+        _ => {
+            return Err(eyre::eyre!(
+                "usage: 04 [--schema FILE] [--keys strict|lenient] \
+                 [--duplicates first|last|error] [--required full|north-pole|KEYS] \
+                 [--compare A,B] [--export pairs|json|csv] | \
+                 --import pairs|json|csv FILE | --stream FILE | --bench [passports]\n\
+                 --required adds a line next to part 1 and sets the fields the strict \
+                 checks need, part 1 itself always requires the North Pole credentials"
+            ))
+        }
+    };
+
     let raw = std::fs::read_to_string("data/04.txt")?;
 
+    // parsed once, then validated in several ways
+    let batch: Batch<Passport> = Batch::parse_with(&raw, options)?;
+
     // first part
-    let batch: Batch<Passport> = Batch::parse(&raw);
-    println!("There are {} valid passports", batch.number_of_valid());
+    println!(
+        "There are {} valid passports",
+        batch.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+    );
+    // the presets are counted already
+    let extra = required.filter(|&required| {
+        required != RequiredFields::FULL_PASSPORT
+            && required != RequiredFields::NORTH_POLE_CREDENTIALS
+    });
+    for policy in std::iter::once(RequiredFields::FULL_PASSPORT).chain(extra) {
+        println!(
+            "There are {} valid {}",
+            batch.number_of_valid(policy),
+            policy
+        );
+    }
 
     // second part
-    let batch: Batch<StrictPassport> = Batch::parse(&raw);
+    let strict = batch.view(Strategy::STRICT);
     println!(
         "There are {} valid passports with strich validation",
-        batch.number_of_valid()
+        strict.number_of_valid(policy)
     );
+    for (reason, count) in strict.common_failures(policy).iter().take(5) {
+        println!("{:>5} rejected as {}", count, reason);
+    }
+    for (reason, passports) in strict.invalid_by_reason(policy) {
+        if let Some(idx) = passports.first() {
+            println!(
+                "e.g. {} in passport {}: {}",
+                reason,
+                idx + 1,
+                strict.0[*idx].validate(policy)
+            );
+        }
+    }
 
-    Ok(())
-}
-
-trait PassportValidation {
-    fn is_valid(&self) -> bool;
-}
+    println!(
+        "There are {} valid passports with cross-field checks",
+        batch.view(Strategy::CHECKED).number_of_valid(policy)
+    );
 
-struct Batch<P>(Vec<P>);
+    let (a, b) = compare;
+    let disagreements = batch.disagreements(a, b, policy);
+    println!(
+        "{} passports are valid as {} but not as {}, or the other way around",
+        disagreements.len(),
+        a,
+        b
+    );
+    for (idx, first, second) in disagreements.iter().take(10) {
+        println!("  passport {}", idx + 1);
+        println!("    {}: {}", a, first);
+        println!("    {}: {}", b, second);
+    }
 
-impl<'r, P> Batch<P>
-where
-    P: PassportValidation + From<&'r str>,
-{
-    fn parse(raw: &'r str) -> Self {
-        let list = raw.split("\n\n").map(|raw| P::from(raw)).collect();
+    println!(
+        "There are {} passports following the schema",
+        batch.number_conforming(&schema)
+    );
 
-        Self(list)
-    }
+    Ok(())
+}
 
-    fn number_of_valid(&self) -> usize {
-        self.0.iter().map(P::is_valid).filter(|&ok| ok).count()
+fn bench(passports: usize) {
+    let raw = generate(passports, 0x2020_0404);
+
+    let time = |name: &str, valid: Option<usize>, elapsed: std::time::Duration| {
+        let elapsed = elapsed.as_secs_f64();
+        println!(
+            "{:<15}: {} passports{} in {:.1}ms, {:.1}M passports/s, {:.1} MB/s",
+            name,
+            passports,
+            valid.map_or(String::new(), |valid| format!(" ({} valid)", valid)),
+            elapsed * 1e3,
+            passports as f64 / elapsed / 1e6,
+            raw.len() as f64 / elapsed / 1e6
+        );
+    };
+
+    // each batch is parsed once, then validated by every strategy like `main` does
+    let start = std::time::Instant::now();
+    let owned: Vec<OwnedPassport> = records(&raw).map(OwnedPassport::parse).collect();
+    time("owned parse", None, start.elapsed());
+    let start = std::time::Instant::now();
+    let batch: Batch<Passport> = Batch::parse(&raw);
+    time("parse", None, start.elapsed());
+
+    let required = RequiredFields::NORTH_POLE_CREDENTIALS;
+    for strategy in [Strategy::LENIENT, Strategy::STRICT, Strategy::CHECKED] {
+        let start = std::time::Instant::now();
+        let valid = Batch(owned.iter().map(|p| (strategy.view)(&p.view())).collect())
+            .number_of_valid(required);
+        time(&format!("owned {}", strategy), Some(valid), start.elapsed());
+
+        let start = std::time::Instant::now();
+        let valid = batch.view(strategy).number_of_valid(required);
+        time(strategy.name, Some(valid), start.elapsed());
     }
 }
 
+/// A passport owning its values, the way passports were kept before they borrowed from
+/// the batch. Only the benchmark uses it, as a baseline.
 #[derive(Debug, Default)]
-struct Passport {
+struct OwnedPassport {
     byr: String,
     iyr: String,
     eyr: String,
@@ -46,329 +203,1758 @@ struct Passport {
     ecl: String,
     pid: String,
     cid: String,
+    extra: std::collections::BTreeMap<String, String>,
 }
 
-impl PassportValidation for Passport {
-    fn is_valid(&self) -> bool {
-        !self.byr.is_empty()
-            && !self.iyr.is_empty()
-            && !self.eyr.is_empty()
-            && !self.hgt.is_empty()
-            && !self.hcl.is_empty()
-            && !self.ecl.is_empty()
-            && !self.pid.is_empty()
+impl OwnedPassport {
+    fn parse(raw: &str) -> Self {
+        let mut passport = OwnedPassport::default();
+        let extra = parse_pairs(raw, ParseOptions::default(), &KEYS, |key, v| {
+            let field = match key {
+                "byr" => &mut passport.byr,
+                "iyr" => &mut passport.iyr,
+                "eyr" => &mut passport.eyr,
+                "hgt" => &mut passport.hgt,
+                "hcl" => &mut passport.hcl,
+                "ecl" => &mut passport.ecl,
+                "pid" => &mut passport.pid,
+                "cid" => &mut passport.cid,
+                _ => unreachable!(),
+            };
+            *field = v.to_owned();
+        })
+        .expect("lenient parsing never fails");
+        passport.extra = extra
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+
+        passport
+    }
+
+    /// Borrows the values, to validate them the same way as a parsed `Passport`.
+    fn view(&self) -> Passport<'_> {
+        Passport {
+            byr: &self.byr,
+            iyr: &self.iyr,
+            eyr: &self.eyr,
+            hgt: &self.hgt,
+            hcl: &self.hcl,
+            ecl: &self.ecl,
+            pid: &self.pid,
+            cid: &self.cid,
+            extra: self
+                .extra
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+        }
     }
 }
 
-impl From<&str> for Passport {
-    fn from(raw: &str) -> Self {
-        let mut passport = Passport::default();
-        for raw_pair in raw.split_whitespace() {
-            let parts: Vec<&str> = raw_pair.split(":").collect();
-            match parts.as_slice() {
-                ["byr", v] => passport.byr = v.to_string(),
-                ["iyr", v] => passport.iyr = v.to_string(),
-                ["eyr", v] => passport.eyr = v.to_string(),
-                ["hgt", v] => passport.hgt = v.to_string(),
-                ["hcl", v] => passport.hcl = v.to_string(),
-                ["ecl", v] => passport.ecl = v.to_string(),
-                ["pid", v] => passport.pid = v.to_string(),
-                ["cid", v] => passport.cid = v.to_string(),
-                [] | [..] => unreachable!(),
-            };
+/// Generates a batch of `n` passports, in random key order and with some fields
+/// missing or out of range.
+fn generate(n: usize, seed: u64) -> String {
+    let mut rng = XorShift::new(seed);
+    let mut next = |bound: usize| rng.below(bound);
+
+    let mut raw = String::new();
+    for idx in 0..n {
+        if idx > 0 {
+            raw.push_str("\n\n");
         }
 
-        passport
+        let hex = "0123456789abcdef".as_bytes();
+        let colour: String = (0..6).map(|_| hex[next(16)] as char).collect();
+        let mut fields = vec![
+            format!("byr:{}", 1910 + next(100)),
+            format!("iyr:{}", 2005 + next(20)),
+            format!("eyr:{}", 2015 + next(20)),
+            match next(3) {
+                0 => format!("hgt:{}in", 50 + next(30)),
+                1 => format!("hgt:{}cm", 140 + next(60)),
+                _ => format!("hgt:{}", 140 + next(60)),
+            },
+            format!("hcl:#{}", colour),
+            format!(
+                "ecl:{}",
+                ["amb", "blu", "brn", "gry", "grn", "hzl", "oth", "xry"][next(8)]
+            ),
+            format!("pid:{:09}", next(1_000_000_000)),
+            format!("cid:{}", 100 + next(250)),
+        ];
+        if next(4) == 0 {
+            fields.swap_remove(next(fields.len()));
+        }
+        if next(8) == 0 {
+            // keys the passports do not know, with values that need escaping
+            let value = ["", "\"quoted\"", "a,b", "back\\slash"][next(4)];
+            fields.push(format!("x{}:{}", next(3), value));
+        }
+        for idx in (1..fields.len()).rev() {
+            fields.swap(idx, next(idx + 1));
+        }
+
+        for (idx, field) in fields.iter().enumerate() {
+            if idx > 0 {
+                raw.push(if next(4) == 0 { '\n' } else { ' ' });
+            }
+            raw.push_str(field);
+        }
     }
+
+    raw
 }
 
-trait PassportField {
-    type Value;
+/// Removes `name` and its value from `args`, wherever they are.
+fn take_option<'a>(args: &mut Vec<&'a str>, name: &str) -> eyre::Result<Option<&'a str>> {
+    match args.iter().position(|&arg| arg == name) {
+        Some(idx) if idx + 1 < args.len() => Ok(args.drain(idx..=idx + 1).nth(1)),
+        Some(_) => Err(eyre::eyre!("{} needs a value", name)),
+        None => Ok(None),
+    }
+}
 
-    fn value(&self) -> Self::Value;
-    fn parse(raw: &str) -> Self;
-    fn is_valid(&self) -> bool;
+trait PassportValidation {
+    /// Checks the fields, reporting the ones missing only if they are `required`.
+    fn validate(&self, required: RequiredFields) -> ValidationReport;
+
+    fn is_valid(&self, required: RequiredFields) -> bool {
+        self.validate(required).is_valid()
+    }
 }
 
-#[derive(Default)]
-struct BirthYear(Option<i16>);
+/// The keys of the fields a passport knows.
+const KEYS: [&str; 8] = ["byr", "iyr", "eyr", "hgt", "hcl", "ecl", "pid", "cid"];
 
-impl PassportField for BirthYear {
-    type Value = Option<i16>;
+/// The fields a passport must have, as a set of indices in [`KEYS`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RequiredFields(u8);
 
-    fn value(&self) -> Self::Value {
-        self.0
+impl RequiredFields {
+    const FULL_PASSPORT: Self = RequiredFields(0b1111_1111);
+    /// Every field but `cid`, which North Pole credentials do not have.
+    const NORTH_POLE_CREDENTIALS: Self = RequiredFields(0b0111_1111);
+
+    fn contains(self, key: &str) -> bool {
+        self.keys().any(|k| k == key)
     }
 
-    fn parse(raw: &str) -> Self {
-        let year = raw.parse::<i16>();
-        match year {
-            Ok(y) => Self(Some(y)),
-            _ => Self(None),
+    fn keys(self) -> impl Iterator<Item = &'static str> {
+        (0..KEYS.len())
+            .filter(move |idx| self.0 & 1 << idx != 0)
+            .map(|idx| KEYS[idx])
+    }
+}
+
+impl std::str::FromStr for RequiredFields {
+    type Err = eyre::Report;
+
+    /// Parses `full`, `north-pole` or a list of keys such as `byr,iyr,pid`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(RequiredFields::FULL_PASSPORT),
+            "north-pole" => Ok(RequiredFields::NORTH_POLE_CREDENTIALS),
+            keys => keys.split(',').try_fold(RequiredFields(0), |set, key| {
+                match KEYS.iter().position(|&k| k == key.trim()) {
+                    Some(idx) => Ok(RequiredFields(set.0 | 1 << idx)),
+                    None => Err(eyre::eyre!("unknown field {:?}", key)),
+                }
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for RequiredFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            RequiredFields::FULL_PASSPORT => write!(f, "full passports"),
+            RequiredFields::NORTH_POLE_CREDENTIALS => write!(f, "North Pole credentials"),
+            _ => write!(
+                f,
+                "passports with {}",
+                self.keys().collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
+/// Why a field was rejected.
+#[derive(Clone, Debug, PartialEq)]
+enum FieldError {
+    Missing,
+    /// The raw value could not be read as the type of the field.
+    Unparsable(String),
+    OutOfRange {
+        value: i16,
+        min: i16,
+        max: i16,
+        /// The unit of the value and bounds, if any.
+        unit: &'static str,
+    },
+    /// The raw value does not look like `expected`.
+    BadFormat {
+        raw: String,
+        expected: &'static str,
+    },
+    /// A rule across fields does not hold for their `values`.
+    Violated {
+        condition: &'static str,
+        values: String,
+    },
+}
+
+impl FieldError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            FieldError::Missing => ErrorKind::Missing,
+            FieldError::Unparsable(_) => ErrorKind::Unparsable,
+            FieldError::OutOfRange { .. } => ErrorKind::OutOfRange,
+            FieldError::BadFormat { .. } => ErrorKind::BadFormat,
+            FieldError::Violated { .. } => ErrorKind::Violated,
+        }
+    }
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldError::Missing => write!(f, "missing"),
+            FieldError::Unparsable(raw) => write!(f, "cannot parse {:?}", raw),
+            FieldError::OutOfRange {
+                value,
+                min,
+                max,
+                unit,
+            } => write!(
+                f,
+                "{}{u} is not within {}{u}..={}{u}",
+                value,
+                min,
+                max,
+                u = unit
+            ),
+            FieldError::BadFormat { raw, expected } => {
+                write!(f, "{:?} does not look like {}", raw, expected)
+            }
+            FieldError::Violated { condition, values } => {
+                write!(f, "`{}` does not hold for {}", condition, values)
+            }
         }
     }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum ErrorKind {
+    Missing,
+    Unparsable,
+    OutOfRange,
+    BadFormat,
+    Violated,
+}
 
+/// A kind of error on a given field, without the details, so that failures can be
+/// grouped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Reason {
+    key: &'static str,
+    kind: ErrorKind,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            ErrorKind::Missing => "missing",
+            ErrorKind::Unparsable => "unparsable",
+            ErrorKind::OutOfRange => "out of range",
+            ErrorKind::BadFormat => "badly formatted",
+            ErrorKind::Violated => "violated",
+        };
+        write!(f, "{} {}", self.key, kind)
+    }
+}
+
+/// Every error found in a passport, by field key.
+#[derive(Debug, Default, PartialEq)]
+struct ValidationReport {
+    errors: Vec<(&'static str, FieldError)>,
+}
+
+impl ValidationReport {
     fn is_valid(&self) -> bool {
-        if let Some(year) = self.value() {
-            1920 <= year && year <= 2002
-        } else {
-            false
+        self.errors.is_empty()
+    }
+
+    /// Records the error of `field`, if it has one. A missing field is only an error
+    /// when it is `required`.
+    fn check<'a, F: PassportField<'a>>(&mut self, field: &F, required: RequiredFields) {
+        match field.validate() {
+            Ok(()) => {}
+            Err(FieldError::Missing) if !required.contains(F::KEY) => {}
+            Err(err) => self.errors.push((F::KEY, err)),
         }
     }
+
+    fn reasons(&self) -> impl Iterator<Item = Reason> + '_ {
+        self.errors.iter().map(|(key, err)| Reason {
+            key,
+            kind: err.kind(),
+        })
+    }
 }
 
-#[derive(Default)]
-struct IssueYear(Option<i16>);
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_valid() {
+            return write!(f, "valid");
+        }
+        for (idx, (key, err)) in self.errors.iter().enumerate() {
+            if idx > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: {}", key, err)?;
+        }
+
+        Ok(())
+    }
+}
 
-impl PassportField for IssueYear {
-    type Value = Option<i16>;
+struct Batch<P>(Vec<P>);
 
-    fn value(&self) -> Self::Value {
-        self.0
+impl<'r, P> Batch<P>
+where
+    P: From<&'r str>,
+{
+    fn parse(raw: &'r str) -> Self {
+        let list = records(raw).map(|raw| P::from(raw)).collect();
+
+        Self(list)
+    }
+}
+
+impl<'r, P: ParsePassport<'r>> Batch<P> {
+    /// Parses every passport with `options`, failing on the first error.
+    fn parse_with(raw: &'r str, options: ParseOptions) -> eyre::Result<Self> {
+        let list = records(raw)
+            .enumerate()
+            .map(|(idx, record)| {
+                P::parse_with(record, options).map_err(|err| {
+                    // the record is a slice of `raw`, so the error can point into it
+                    let start = record.as_ptr() as usize - raw.as_ptr() as usize;
+                    let (line, column) = line_and_column(raw, start + err.offset);
+                    eyre::eyre!(
+                        "passport {}, line {}, column {}: {}",
+                        idx + 1,
+                        line,
+                        column,
+                        err.kind
+                    )
+                })
+            })
+            .collect::<eyre::Result<_>>()?;
+
+        Ok(Self(list))
     }
+}
 
-    fn parse(raw: &str) -> Self {
-        let year = raw.parse::<i16>();
-        match year {
-            Ok(y) => Self(Some(y)),
-            _ => Self(None),
+impl<P: PassportValidation> Batch<P> {
+    fn number_of_valid(&self, required: RequiredFields) -> usize {
+        self.0.iter().filter(|p| p.is_valid(required)).count()
+    }
+
+    fn reports(&self, required: RequiredFields) -> Vec<ValidationReport> {
+        self.0.iter().map(|p| p.validate(required)).collect()
+    }
+
+    /// How often each reason rejects a passport, the most common first.
+    fn common_failures(&self, required: RequiredFields) -> Vec<(Reason, usize)> {
+        let mut counts = std::collections::HashMap::new();
+        for report in self.reports(required) {
+            for reason in report.reasons() {
+                *counts.entry(reason).or_insert(0) += 1;
+            }
         }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by_key(|&(reason, count)| (std::cmp::Reverse(count), reason));
+        counts
     }
 
-    fn is_valid(&self) -> bool {
-        if let Some(year) = self.value() {
-            2010 <= year && year <= 2020
-        } else {
-            false
+    /// The indices of the invalid passports, grouped by reason. A passport with
+    /// several errors is in several groups.
+    fn invalid_by_reason(
+        &self,
+        required: RequiredFields,
+    ) -> std::collections::BTreeMap<Reason, Vec<usize>> {
+        let mut groups = std::collections::BTreeMap::new();
+        for (idx, report) in self.reports(required).iter().enumerate() {
+            for reason in report.reasons() {
+                groups.entry(reason).or_insert_with(Vec::new).push(idx);
+            }
         }
+
+        groups
     }
 }
 
-#[derive(Default)]
-struct ExpirationYear(Option<i16>);
+impl<P: Fields> Batch<P> {
+    fn number_conforming(&self, schema: &Schema) -> usize {
+        self.0.iter().filter(|p| schema.check(*p)).count()
+    }
+}
 
-impl PassportField for ExpirationYear {
-    type Value = Option<i16>;
+/// The passports of a batch, separated by blank lines.
+///
+/// Lines may end with `\r\n` or trailing whitespace, and blank lines may come in runs.
+fn records(raw: &str) -> impl Iterator<Item = &str> {
+    let mut lines = raw.split_inclusive('\n');
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let mut record: Option<(usize, usize)> = None;
+        for line in lines.by_ref() {
+            let start = offset;
+            offset += line.len();
+            if line.trim().is_empty() {
+                if record.is_some() {
+                    break;
+                }
+                continue;
+            }
+
+            let end = start + line.trim_end().len();
+            record = Some((record.map_or(start, |(start, _)| start), end));
+        }
 
-    fn value(&self) -> Self::Value {
-        self.0
+        record.map(|(start, end)| &raw[start..end])
+    })
+}
+
+/// Reads a batch one passport at a time, like [`records`], keeping only the current
+/// passport in memory.
+struct RecordReader<R> {
+    reader: R,
+    line: String,
+    record: String,
+    /// The number of lines read so far.
+    lines: usize,
+}
+
+impl<R: std::io::BufRead> RecordReader<R> {
+    fn new(reader: R) -> Self {
+        RecordReader {
+            reader,
+            line: String::new(),
+            record: String::new(),
+            lines: 0,
+        }
     }
 
-    fn parse(raw: &str) -> Self {
-        let year = raw.parse::<i16>();
-        match year {
-            Ok(y) => Self(Some(y)),
-            _ => Self(None),
+    /// The next passport with the (1-based) number of its first line, or `None` at the
+    /// end of the batch. The lines of the passport are joined with `\n`.
+    fn next_record(&mut self) -> eyre::Result<Option<(usize, &str)>> {
+        self.record.clear();
+        let mut first = 0;
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                break;
+            }
+            self.lines += 1;
+
+            let line = self.line.trim_end();
+            if line.trim_start().is_empty() {
+                if self.record.is_empty() {
+                    continue;
+                }
+                break;
+            }
+
+            if self.record.is_empty() {
+                first = self.lines;
+            } else {
+                self.record.push('\n');
+            }
+            self.record.push_str(line);
+        }
+
+        Ok(Some((first, self.record.as_str())).filter(|(_, record)| !record.is_empty()))
+    }
+}
+
+/// Counts the passports valid under each strategy while reading the batch passport by
+/// passport, so the batch is never held in memory. Each passport is parsed once.
+fn count_valid_streaming<R: std::io::BufRead>(
+    reader: R,
+    strategies: &[Strategy],
+    required: RequiredFields,
+) -> eyre::Result<Vec<usize>> {
+    let mut records = RecordReader::new(reader);
+    let mut counts = vec![0; strategies.len()];
+    while let Some((_, record)) = records.next_record()? {
+        let passport = Passport::from(record);
+        for (strategy, count) in strategies.iter().zip(&mut counts) {
+            *count += (strategy.view)(&passport).is_valid(required) as usize;
         }
     }
 
-    fn is_valid(&self) -> bool {
-        if let Some(year) = self.value() {
-            2020 <= year && year <= 2030
+    Ok(counts)
+}
+
+/// What to do with keys a passport does not know, or with a token without `:`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Keys {
+    /// Refuse them.
+    Strict,
+    /// Keep them aside, in the `extra` map of the passport.
+    #[default]
+    Lenient,
+}
+
+impl std::str::FromStr for Keys {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Keys::Strict),
+            "lenient" => Ok(Keys::Lenient),
+            _ => Err(eyre::eyre!("unknown key mode {:?}", s)),
+        }
+    }
+}
+
+/// Which value to keep when a passport has the same key twice.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Duplicates {
+    First,
+    #[default]
+    Last,
+    /// Refuse the passport.
+    Error,
+}
+
+impl std::str::FromStr for Duplicates {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Duplicates::First),
+            "last" => Ok(Duplicates::Last),
+            "error" => Ok(Duplicates::Error),
+            _ => Err(eyre::eyre!("unknown duplicate policy {:?}", s)),
+        }
+    }
+}
+
+/// How forgiving passport parsing is. The default never fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct ParseOptions {
+    keys: Keys,
+    duplicates: Duplicates,
+}
+
+#[derive(Debug, PartialEq)]
+struct ParseError {
+    /// Byte offset of the offending token in the passport.
+    offset: usize,
+    kind: ParseErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseErrorKind {
+    MissingColon(String),
+    UnknownKey(String),
+    DuplicateKey(String),
+}
+
+impl std::fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseErrorKind::MissingColon(token) => {
+                write!(f, "expected `key:value`, found {:?}", token)
+            }
+            ParseErrorKind::UnknownKey(key) => write!(f, "unknown key {:?}", key),
+            ParseErrorKind::DuplicateKey(key) => write!(f, "duplicate key {:?}", key),
+        }
+    }
+}
+
+/// The 1-based line and column of the byte at `offset` in `text`.
+fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// A passport that can be parsed with [`ParseOptions`], borrowing its values from `raw`.
+trait ParsePassport<'r>: Sized {
+    fn parse_with(raw: &'r str, options: ParseOptions) -> Result<Self, ParseError>;
+}
+
+/// Splits a passport into its `key:value` pairs, giving the value of each of the `known`
+/// keys to `set` and returning the other pairs.
+///
+/// A token without `:` is kept aside as an other key with an empty value in lenient
+/// mode, even when it names a known key, and never counts as a duplicate.
+fn parse_pairs<'r>(
+    raw: &'r str,
+    options: ParseOptions,
+    known: &[&str],
+    mut set: impl FnMut(&'r str, &'r str),
+) -> Result<std::collections::BTreeMap<&'r str, &'r str>, ParseError> {
+    let mut extra = std::collections::BTreeMap::new();
+    let mut seen = std::collections::HashSet::new();
+    for token in raw.split_whitespace() {
+        let offset = token.as_ptr() as usize - raw.as_ptr() as usize;
+        let error = |kind| Err(ParseError { offset, kind });
+
+        let (key, value) = match (token.split_once(':'), options.keys) {
+            (Some(pair), _) => pair,
+            (None, Keys::Lenient) => {
+                extra.insert(token, "");
+                continue;
+            }
+            (None, Keys::Strict) => return error(ParseErrorKind::MissingColon(token.to_owned())),
+        };
+        let is_known = known.contains(&key);
+        if !is_known && options.keys == Keys::Strict {
+            return error(ParseErrorKind::UnknownKey(key.to_owned()));
+        }
+
+        // the last value wins anyway, so only the other policies track the keys
+        if options.duplicates != Duplicates::Last && !seen.insert(key) {
+            match options.duplicates {
+                Duplicates::First => continue,
+                Duplicates::Last => {}
+                Duplicates::Error => return error(ParseErrorKind::DuplicateKey(key.to_owned())),
+            }
+        }
+
+        if is_known {
+            set(key, value);
         } else {
-            false
+            extra.insert(key, value);
+        }
+    }
+
+    Ok(extra)
+}
+
+/// The raw values of a passport, borrowed from the batch. A missing field is empty.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Passport<'r> {
+    byr: &'r str,
+    iyr: &'r str,
+    eyr: &'r str,
+    hgt: &'r str,
+    hcl: &'r str,
+    ecl: &'r str,
+    pid: &'r str,
+    cid: &'r str,
+    /// Pairs with other keys, when parsed leniently.
+    extra: std::collections::BTreeMap<&'r str, &'r str>,
+}
+
+impl PassportValidation for Passport<'_> {
+    /// Only checks that the required fields are there.
+    fn validate(&self, required: RequiredFields) -> ValidationReport {
+        let errors = required
+            .keys()
+            .filter(|key| self.field(key).is_none())
+            .map(|key| (key, FieldError::Missing))
+            .collect();
+
+        ValidationReport { errors }
+    }
+}
+
+impl<'r> ParsePassport<'r> for Passport<'r> {
+    fn parse_with(raw: &'r str, options: ParseOptions) -> Result<Self, ParseError> {
+        let mut passport = Passport::default();
+        let extra = parse_pairs(raw, options, &KEYS, |key, v| match key {
+            "byr" => passport.byr = v,
+            "iyr" => passport.iyr = v,
+            "eyr" => passport.eyr = v,
+            "hgt" => passport.hgt = v,
+            "hcl" => passport.hcl = v,
+            "ecl" => passport.ecl = v,
+            "pid" => passport.pid = v,
+            "cid" => passport.cid = v,
+            _ => unreachable!(),
+        })?;
+        passport.extra = extra;
+
+        Ok(passport)
+    }
+}
+
+impl<'r> From<&'r str> for Passport<'r> {
+    fn from(raw: &'r str) -> Self {
+        Self::parse_with(raw, ParseOptions::default()).expect("lenient parsing never fails")
+    }
+}
+
+impl Fields for Passport<'_> {
+    fn field(&self, key: &str) -> Option<&str> {
+        let value = match key {
+            "byr" => self.byr,
+            "iyr" => self.iyr,
+            "eyr" => self.eyr,
+            "hgt" => self.hgt,
+            "hcl" => self.hcl,
+            "ecl" => self.ecl,
+            "pid" => self.pid,
+            "cid" => self.cid,
+            key => return self.extra.get(key).copied(),
+        };
+
+        Some(value).filter(|v| !v.is_empty())
+    }
+}
+
+/// A typed field of a passport, which may borrow its raw value for `'r`.
+trait PassportField<'r> {
+    type Value;
+
+    /// The key of the field in the `key:value` pairs.
+    const KEY: &'static str;
+
+    fn value(&self) -> Self::Value;
+    fn parse(raw: &'r str) -> Self;
+    fn validate(&self) -> Result<(), FieldError>;
+}
+
+/// A field value as read from a passport.
+#[derive(Clone, Debug, Default, PartialEq)]
+enum Parsed<'r, T> {
+    #[default]
+    Missing,
+    /// The raw value, which could not be parsed.
+    Invalid(&'r str),
+    Value(T),
+}
+
+impl<T: Clone> Parsed<'_, T> {
+    fn value(&self) -> Option<T> {
+        match self {
+            Parsed::Value(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// The parsed value, or why there is none.
+    fn get(&self) -> Result<&T, FieldError> {
+        match self {
+            Parsed::Missing => Err(FieldError::Missing),
+            Parsed::Invalid(raw) => Err(FieldError::Unparsable((*raw).to_owned())),
+            Parsed::Value(value) => Ok(value),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Debug, Default, PartialEq, PassportField)]
+#[field(key = "byr", year(1920..=2002))]
+struct BirthYear<'r>(Parsed<'r, i16>);
+
+#[derive(Debug, Default, PartialEq, PassportField)]
+#[field(key = "iyr", year(2010..=2020))]
+struct IssueYear<'r>(Parsed<'r, i16>);
+
+#[derive(Debug, Default, PartialEq, PassportField)]
+#[field(key = "eyr", year(2020..=2030))]
+struct ExpirationYear<'r>(Parsed<'r, i16>);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum HeightUnit {
     In(i16),
     Cm(i16),
 }
 
-#[derive(Default)]
-struct Height(Option<HeightUnit>);
+impl std::fmt::Display for HeightUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeightUnit::In(h) => write!(f, "{}in", h),
+            HeightUnit::Cm(h) => write!(f, "{}cm", h),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Height<'r>(Parsed<'r, HeightUnit>);
 
-impl PassportField for Height {
+impl<'r> PassportField<'r> for Height<'r> {
     type Value = Option<HeightUnit>;
 
+    const KEY: &'static str = "hgt";
+
     fn value(&self) -> Self::Value {
-        self.0
+        self.0.value()
     }
 
-    fn parse(raw: &str) -> Self {
+    fn parse(raw: &'r str) -> Self {
         let len = raw.len();
+        let invalid = Self(Parsed::Invalid(raw));
 
-        if len <= 2 {
-            return Self(None);
+        if len <= 2 || !raw.is_char_boundary(len - 2) {
+            return invalid;
         }
 
         let val = &raw[..(len - 2)];
         let val = val.parse::<i16>();
         if val.is_err() {
-            return Self(None);
+            return invalid;
         }
         let val = val.unwrap();
 
         let unit = &raw[(len - 2)..];
         match unit {
-            "cm" => Self(Some(HeightUnit::Cm(val))),
-            "in" => Self(Some(HeightUnit::In(val))),
-            _ => Self(None),
+            "cm" => Self(Parsed::Value(HeightUnit::Cm(val))),
+            "in" => Self(Parsed::Value(HeightUnit::In(val))),
+            _ => invalid,
         }
     }
 
-    fn is_valid(&self) -> bool {
-        if self.value().is_none() {
-            return false;
+    fn validate(&self) -> Result<(), FieldError> {
+        let (value, min, max, unit) = match *self.0.get()? {
+            HeightUnit::Cm(h) => (h, 150, 193, "cm"),
+            HeightUnit::In(h) => (h, 59, 76, "in"),
+        };
+
+        if (min..=max).contains(&value) {
+            Ok(())
+        } else {
+            Err(FieldError::OutOfRange {
+                value,
+                min,
+                max,
+                unit,
+            })
         }
+    }
+}
 
-        let val = self.value().unwrap();
+#[derive(Debug, Default, PartialEq, PassportField)]
+#[field(key = "hcl", hex_color)]
+struct HairColor<'r>(Parsed<'r, &'r str>);
 
-        match val {
-            HeightUnit::Cm(h) => 150 <= h && h <= 193,
-            HeightUnit::In(h) => 59 <= h && h <= 76,
-        }
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EyeColors {
+    Ambar,
+    Blue,
+    Brown,
+    Grey,
+    Green,
+    Hazel,
+    Other,
+}
+
+impl std::fmt::Display for EyeColors {
+    /// Writes the code of the colour, as read by [`EyeColor::parse`].
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = match self {
+            EyeColors::Ambar => "amb",
+            EyeColors::Blue => "blu",
+            EyeColors::Brown => "brn",
+            EyeColors::Grey => "gry",
+            EyeColors::Green => "grn",
+            EyeColors::Hazel => "hzl",
+            EyeColors::Other => "oth",
+        };
+        write!(f, "{}", code)
     }
 }
 
-#[derive(Default)]
-struct HairColor(Option<String>);
+#[derive(Debug, Default, PartialEq)]
+struct EyeColor<'r>(Parsed<'r, EyeColors>);
 
-impl PassportField for HairColor {
-    type Value = Option<String>;
+impl<'r> PassportField<'r> for EyeColor<'r> {
+    type Value = Option<EyeColors>;
+
+    const KEY: &'static str = "ecl";
 
     fn value(&self) -> Self::Value {
-        self.0.clone()
+        self.0.value()
     }
 
-    fn parse(raw: &str) -> Self {
-        Self(Some(raw.to_owned()))
+    fn parse(raw: &'r str) -> Self {
+        use EyeColors::*;
+
+        let color = match raw {
+            "amb" => Ambar,
+            "blu" => Blue,
+            "brn" => Brown,
+            "gry" => Grey,
+            "grn" => Green,
+            "hzl" => Hazel,
+            "oth" => Other,
+            _ => return Self(Parsed::Invalid(raw)),
+        };
+
+        Self(Parsed::Value(color))
     }
 
-    fn is_valid(&self) -> bool {
-        if self.value().is_none() {
-            return false;
-        }
+    fn validate(&self) -> Result<(), FieldError> {
+        self.0.get().map(|_| ())
+    }
+}
 
-        let val = self.value().unwrap();
+#[derive(Debug, Default, PartialEq, PassportField)]
+#[field(key = "pid", digits(9))]
+struct PassportID<'r>(Parsed<'r, &'r str>);
+
+#[derive(Debug, Default, PartialEq, PassportKeys, PassportRules)]
+#[rule(name = "byr<iyr", check = birth_year < issue_year)]
+#[rule(name = "iyr<=eyr", check = issue_year <= expiration_year)]
+#[rule(name = "age at issue", check = issue_year - birth_year >= 16)]
+#[rule(name = "validity", check = expiration_year - issue_year <= 10)]
+struct StrictPassport<'r> {
+    birth_year: BirthYear<'r>,
+    issue_year: IssueYear<'r>,
+    expiration_year: ExpirationYear<'r>,
+    height: Height<'r>,
+    hair_color: HairColor<'r>,
+    eye_color: EyeColor<'r>,
+    passport_id: PassportID<'r>,
+    #[passport(key = "cid")]
+    country_id: &'r str,
+    #[passport(extra)]
+    extra: std::collections::BTreeMap<&'r str, &'r str>,
+}
 
-        if val.len() != 7 {
-            return false;
+impl PassportValidation for StrictPassport<'_> {
+    fn validate(&self, required: RequiredFields) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        report.check(&self.birth_year, required);
+        report.check(&self.issue_year, required);
+        report.check(&self.expiration_year, required);
+        report.check(&self.height, required);
+        report.check(&self.hair_color, required);
+        report.check(&self.eye_color, required);
+        report.check(&self.passport_id, required);
+        if self.country_id.is_empty() && required.contains("cid") {
+            report.errors.push(("cid", FieldError::Missing));
         }
 
-        if &val[0..1] != "#" {
-            return false;
+        report
+    }
+}
+
+/// Rules across the fields of a passport, checked once each field is valid.
+trait PassportRules {
+    fn check_rules(&self, report: &mut ValidationReport);
+}
+
+/// A passport validated with its [`PassportRules`] on top of its own validation.
+#[derive(Debug, Default, PartialEq)]
+struct Checked<P>(P);
+
+impl<P: PassportValidation + PassportRules> PassportValidation for Checked<P> {
+    fn validate(&self, required: RequiredFields) -> ValidationReport {
+        let mut report = self.0.validate(required);
+        self.0.check_rules(&mut report);
+
+        report
+    }
+}
+
+impl<'r, P: ParsePassport<'r>> ParsePassport<'r> for Checked<P> {
+    fn parse_with(raw: &'r str, options: ParseOptions) -> Result<Self, ParseError> {
+        P::parse_with(raw, options).map(Checked)
+    }
+}
+
+impl<'r, P: From<&'r str>> From<&'r str> for Checked<P> {
+    fn from(raw: &'r str) -> Self {
+        Checked(P::from(raw))
+    }
+}
+
+impl<P: PassportValidation + ?Sized> PassportValidation for Box<P> {
+    fn validate(&self, required: RequiredFields) -> ValidationReport {
+        (**self).validate(required)
+    }
+}
+
+impl<'r> From<&Passport<'r>> for StrictPassport<'r> {
+    /// Parses the raw values of a passport, an empty one being missing.
+    fn from(passport: &Passport<'r>) -> Self {
+        fn field<'r, F: PassportField<'r> + Default>(raw: &'r str) -> F {
+            if raw.is_empty() {
+                F::default()
+            } else {
+                F::parse(raw)
+            }
         }
 
-        val[1..].chars().into_iter().all(|c| c.is_ascii_hexdigit())
+        StrictPassport {
+            birth_year: field(passport.byr),
+            issue_year: field(passport.iyr),
+            expiration_year: field(passport.eyr),
+            height: field(passport.hgt),
+            hair_color: field(passport.hcl),
+            eye_color: field(passport.ecl),
+            passport_id: field(passport.pid),
+            country_id: passport.cid,
+            extra: passport.extra.clone(),
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-enum EyeColors {
-    Ambar,
-    Blue,
-    Brown,
-    Grey,
-    Green,
-    Hazel,
-    Other,
+/// A way of validating passports, chosen at runtime, which builds the passport it
+/// validates from the raw values of a [`Passport`].
+#[derive(Clone, Copy)]
+struct Strategy {
+    name: &'static str,
+    view: for<'p, 'r> fn(&'p Passport<'r>) -> Box<dyn PassportValidation + 'r>,
 }
 
-#[derive(Default)]
-struct EyeColor(Option<EyeColors>);
+impl Strategy {
+    /// Only checks that the fields are there.
+    const LENIENT: Strategy = Strategy {
+        name: "lenient",
+        view: |passport| Box::new(passport.clone()),
+    };
+    const STRICT: Strategy = Strategy {
+        name: "strict",
+        view: |passport| Box::new(StrictPassport::from(passport)),
+    };
+    /// Strict, with the [`PassportRules`] on top.
+    const CHECKED: Strategy = Strategy {
+        name: "checked",
+        view: |passport| Box::new(Checked(StrictPassport::from(passport))),
+    };
+}
 
-impl PassportField for EyeColor {
-    type Value = Option<EyeColors>;
+impl std::str::FromStr for Strategy {
+    type Err = eyre::Report;
 
-    fn value(&self) -> Self::Value {
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Strategy::LENIENT, Strategy::STRICT, Strategy::CHECKED]
+            .iter()
+            .copied()
+            .find(|strategy| strategy.name == s)
+            .ok_or_else(|| eyre::eyre!("unknown strategy {:?}", s))
+    }
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl<'r> Batch<Passport<'r>> {
+    /// The passports as `strategy` validates them, without parsing them again.
+    fn view(&self, strategy: Strategy) -> Batch<Box<dyn PassportValidation + 'r>> {
+        Batch(self.0.iter().map(strategy.view).collect())
+    }
+
+    /// The passports valid under one strategy but not the other, by index, with the
+    /// report of each strategy.
+    fn disagreements(
+        &self,
+        a: Strategy,
+        b: Strategy,
+        required: RequiredFields,
+    ) -> Vec<(usize, ValidationReport, ValidationReport)> {
         self.0
+            .iter()
+            .enumerate()
+            .map(|(idx, passport)| {
+                let first = (a.view)(passport).validate(required);
+                let second = (b.view)(passport).validate(required);
+                (idx, first, second)
+            })
+            .filter(|(_, first, second)| first.is_valid() != second.is_valid())
+            .collect()
     }
+}
 
-    fn parse(raw: &str) -> Self {
-        use EyeColors::*;
+/// A field value as exported, typed when the passport knows the type of the field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value<'r> {
+    Year(i16),
+    Height(HeightUnit),
+    Eyes(EyeColors),
+    /// A raw value, either untyped or which could not be parsed.
+    Text(&'r str),
+}
 
-        match raw {
-            "amb" => Self(Some(Ambar)),
-            "blu" => Self(Some(Blue)),
-            "brn" => Self(Some(Brown)),
-            "gry" => Self(Some(Grey)),
-            "grn" => Self(Some(Green)),
-            "hzl" => Self(Some(Hazel)),
-            "oth" => Self(Some(Other)),
-            _ => Self(None),
+impl std::fmt::Display for Value<'_> {
+    /// Writes the value as it appears in a `key:value` pair.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Year(year) => write!(f, "{}", year),
+            Value::Height(height) => write!(f, "{}", height),
+            Value::Eyes(colour) => write!(f, "{}", colour),
+            Value::Text(raw) => write!(f, "{}", raw),
         }
     }
+}
 
-    fn is_valid(&self) -> bool {
-        self.value().is_some()
+impl<'r, T> Parsed<'r, T> {
+    /// The value for export, the raw one if it could not be parsed.
+    fn export(&self, typed: impl Fn(&T) -> Value<'r>) -> Option<Value<'r>> {
+        match self {
+            Parsed::Missing => None,
+            Parsed::Invalid(raw) => Some(Value::Text(raw)),
+            Parsed::Value(value) => Some(typed(value)),
+        }
     }
 }
 
-#[derive(Default)]
-struct PassportID(Option<String>);
+/// A passport that can be written out, as the pairs it would be parsed from.
+trait Export {
+    fn pairs(&self) -> Vec<(&str, Value<'_>)>;
+}
 
-impl PassportField for PassportID {
-    type Value = Option<String>;
+impl Export for Passport<'_> {
+    fn pairs(&self) -> Vec<(&str, Value<'_>)> {
+        let fields = [
+            ("byr", self.byr),
+            ("iyr", self.iyr),
+            ("eyr", self.eyr),
+            ("hgt", self.hgt),
+            ("hcl", self.hcl),
+            ("ecl", self.ecl),
+            ("pid", self.pid),
+            ("cid", self.cid),
+        ];
+
+        fields
+            .iter()
+            .copied()
+            .filter(|(_, value)| !value.is_empty())
+            .chain(self.extra.iter().map(|(key, value)| (*key, *value)))
+            .map(|(key, value)| (key, Value::Text(value)))
+            .collect()
+    }
+}
 
-    fn value(&self) -> Self::Value {
-        self.0.clone()
+impl Export for StrictPassport<'_> {
+    fn pairs(&self) -> Vec<(&str, Value<'_>)> {
+        let fields = [
+            (
+                BirthYear::KEY,
+                self.birth_year.0.export(|&y| Value::Year(y)),
+            ),
+            (
+                IssueYear::KEY,
+                self.issue_year.0.export(|&y| Value::Year(y)),
+            ),
+            (
+                ExpirationYear::KEY,
+                self.expiration_year.0.export(|&y| Value::Year(y)),
+            ),
+            (Height::KEY, self.height.0.export(|&h| Value::Height(h))),
+            (HairColor::KEY, self.hair_color.0.export(|c| Value::Text(c))),
+            (EyeColor::KEY, self.eye_color.0.export(|&c| Value::Eyes(c))),
+            (
+                PassportID::KEY,
+                self.passport_id.0.export(|id| Value::Text(id)),
+            ),
+            (
+                "cid",
+                Some(Value::Text(self.country_id)).filter(|_| !self.country_id.is_empty()),
+            ),
+        ];
+
+        fields
+            .iter()
+            .filter_map(|&(key, value)| Some((key, value?)))
+            .chain(
+                self.extra
+                    .iter()
+                    .map(|(key, value)| (*key, Value::Text(value))),
+            )
+            .collect()
     }
+}
 
-    fn parse(raw: &str) -> Self {
-        Self(Some(raw.to_owned()))
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// The `key:value` pairs of the puzzle input.
+    Pairs,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pairs" => Ok(Format::Pairs),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(eyre::eyre!("unknown format {:?}", s)),
+        }
     }
+}
 
-    fn is_valid(&self) -> bool {
-        if self.value().is_none() {
-            return false;
+impl<P: Export> Batch<P> {
+    /// Writes the passports in `format`.
+    ///
+    /// In JSON, each passport is an object with a typed value per field: years are
+    /// numbers, heights `{"value": 183, "unit": "cm"}` objects and everything else
+    /// strings. In CSV, there is a column for every key of the batch, and an empty cell
+    /// is a missing field.
+    fn write<W: std::io::Write>(&self, format: Format, out: &mut W) -> std::io::Result<()> {
+        let passports: Vec<_> = self.0.iter().map(P::pairs).collect();
+
+        match format {
+            Format::Pairs => {
+                for (idx, pairs) in passports.iter().enumerate() {
+                    if idx > 0 {
+                        writeln!(out)?;
+                    }
+                    // an empty value was a token without `:`, written back as such so
+                    // that it reads back into the other keys
+                    let pairs: Vec<_> = pairs
+                        .iter()
+                        .map(|(key, value)| match value {
+                            Value::Text("") => key.to_string(),
+                            value => format!("{}:{}", key, value),
+                        })
+                        .collect();
+                    writeln!(out, "{}", pairs.join(" "))?;
+                }
+            }
+            Format::Json => {
+                writeln!(out, "[")?;
+                for (idx, pairs) in passports.iter().enumerate() {
+                    let separator = if idx + 1 < passports.len() { "," } else { "" };
+                    let fields: Vec<_> = pairs
+                        .iter()
+                        .map(|(key, value)| {
+                            let value = match value {
+                                Value::Year(year) => year.to_string(),
+                                Value::Height(HeightUnit::Cm(h)) => {
+                                    format!("{{\"value\": {}, \"unit\": \"cm\"}}", h)
+                                }
+                                Value::Height(HeightUnit::In(h)) => {
+                                    format!("{{\"value\": {}, \"unit\": \"in\"}}", h)
+                                }
+                                value => json_string(&value.to_string()),
+                            };
+                            format!("{}: {}", json_string(key), value)
+                        })
+                        .collect();
+                    writeln!(out, "  {{{}}}{}", fields.join(", "), separator)?;
+                }
+                writeln!(out, "]")?;
+            }
+            Format::Csv => {
+                let mut keys: Vec<&str> = vec![];
+                for (key, _) in passports.iter().flatten() {
+                    if !keys.contains(key) {
+                        keys.push(key);
+                    }
+                }
+
+                let header: Vec<_> = keys.iter().map(|key| csv_field(key)).collect();
+                writeln!(out, "{}", header.join(","))?;
+                for pairs in &passports {
+                    let cells: Vec<_> = keys
+                        .iter()
+                        .map(|key| match pairs.iter().find(|(k, _)| k == key) {
+                            // quoted, so that it is not mistaken for a missing field
+                            Some((_, Value::Text(""))) => "\"\"".to_owned(),
+                            Some((_, value)) => csv_field(&value.to_string()),
+                            None => String::new(),
+                        })
+                        .collect();
+                    writeln!(out, "{}", cells.join(","))?;
+                }
+            }
         }
 
-        let val = self.value().unwrap();
+        Ok(())
+    }
+}
 
-        if val.len() != 9 {
-            return false;
+/// Converts passports written by [`Batch::write`] back to `key:value` pairs, in which
+/// [`Batch::parse`] reads them.
+fn import(src: &str, format: Format) -> eyre::Result<String> {
+    let passports: Vec<Vec<(String, String)>> = match format {
+        Format::Pairs => return Ok(src.to_owned()),
+        Format::Json => {
+            let passports = match Json::parse(src)? {
+                Json::Array(passports) => passports,
+                _ => return Err(eyre::eyre!("expected an array of passports")),
+            };
+            passports
+                .into_iter()
+                .enumerate()
+                .map(|(idx, passport)| {
+                    json_pairs(passport).map_err(|err| eyre::eyre!("passport {}: {}", idx + 1, err))
+                })
+                .collect::<eyre::Result<_>>()?
+        }
+        Format::Csv => {
+            let mut rows = csv_rows(src)?.into_iter();
+            let header = rows.next().unwrap_or_default();
+            rows.enumerate()
+                .map(|(idx, row)| {
+                    if row.len() != header.len() {
+                        return Err(eyre::eyre!(
+                            "row {}: expected {} cells, found {}",
+                            idx + 2,
+                            header.len(),
+                            row.len()
+                        ));
+                    }
+                    Ok(header
+                        .iter()
+                        .zip(row)
+                        .filter(|(_, (cell, quoted))| *quoted || !cell.is_empty())
+                        .map(|((key, _), (cell, _))| (key.clone(), cell))
+                        .collect())
+                })
+                .collect::<eyre::Result<_>>()?
         }
+    };
 
-        val.chars().all(|c| c.is_ascii_digit())
+    let mut raw = String::new();
+    for (idx, pairs) in passports.iter().enumerate() {
+        if idx > 0 {
+            raw.push_str("\n\n");
+        }
+        for (pos, (key, value)) in pairs.iter().enumerate() {
+            let blank = |s: &str| s.contains(char::is_whitespace);
+            if blank(key) || key.contains(':') || blank(value) {
+                return Err(eyre::eyre!(
+                    "passport {}: {:?}: {:?} cannot be written as `key:value`",
+                    idx + 1,
+                    key,
+                    value
+                ));
+            }
+            if pos > 0 {
+                raw.push(' ');
+            }
+            raw.push_str(key);
+            raw.push(':');
+            raw.push_str(value);
+        }
     }
+
+    Ok(raw)
 }
 
-#[derive(Default)]
-struct StrictPassport {
-    birth_year: BirthYear,
-    issue_year: IssueYear,
-    expiration_year: ExpirationYear,
-    height: Height,
-    hair_color: HairColor,
-    eye_color: EyeColor,
-    passport_id: PassportID,
-    country_id: String,
+/// The pairs of a passport written as a JSON object, turning typed values back to raw
+/// ones. `null` is a missing field.
+fn json_pairs(passport: Json) -> eyre::Result<Vec<(String, String)>> {
+    let fields = match passport {
+        Json::Object(fields) => fields,
+        _ => return Err(eyre::eyre!("expected an object")),
+    };
+
+    let mut pairs = vec![];
+    for (key, value) in fields {
+        let raw = match value {
+            Json::Null => continue,
+            Json::String(raw) | Json::Number(raw) => raw,
+            Json::Object(height) => match &height[..] {
+                [(v, Json::Number(value)), (u, Json::String(unit))]
+                    if v == "value" && u == "unit" =>
+                {
+                    format!("{}{}", value, unit)
+                }
+                _ => {
+                    return Err(eyre::eyre!(
+                        "expected {{\"value\", \"unit\"}} for {:?}",
+                        key
+                    ))
+                }
+            },
+            _ => return Err(eyre::eyre!("unsupported value for {:?}", key)),
+        };
+        pairs.push((key, raw));
+    }
+
+    Ok(pairs)
 }
 
-impl PassportValidation for StrictPassport {
-    fn is_valid(&self) -> bool {
-        self.birth_year.is_valid()
-            && self.issue_year.is_valid()
-            && self.expiration_year.is_valid()
-            && self.height.is_valid()
-            && self.hair_color.is_valid()
-            && self.eye_color.is_valid()
-            && self.passport_id.is_valid()
-    }
-}
-
-impl From<&str> for StrictPassport {
-    fn from(raw: &str) -> Self {
-        let mut passport = StrictPassport::default();
-        for raw_pair in raw.split_whitespace() {
-            let parts: Vec<&str> = raw_pair.split(":").collect();
-            match parts.as_slice() {
-                ["byr", v] => passport.birth_year = BirthYear::parse(v),
-                ["iyr", v] => passport.issue_year = IssueYear::parse(v),
-                ["eyr", v] => passport.expiration_year = ExpirationYear::parse(v),
-                ["hgt", v] => passport.height = Height::parse(v),
-                ["hcl", v] => passport.hair_color = HairColor::parse(v),
-                ["ecl", v] => passport.eye_color = EyeColor::parse(v),
-                ["pid", v] => passport.passport_id = PassportID::parse(v),
-                ["cid", v] => passport.country_id = v.to_string(),
-                [] | [..] => unreachable!(),
-            };
+/// The cells of every row of a CSV document, each telling whether it was quoted.
+fn csv_rows(src: &str) -> eyre::Result<Vec<Vec<(String, bool)>>> {
+    let mut rows = vec![];
+    let mut chars = src.chars().peekable();
+    while chars.peek().is_some() {
+        let mut row = vec![];
+        loop {
+            let mut cell = String::new();
+            let quoted = chars.peek() == Some(&'"');
+            if quoted {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            cell.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => cell.push(c),
+                        None => {
+                            return Err(eyre::eyre!(
+                                "row {}: unterminated quoted cell",
+                                rows.len() + 1
+                            ))
+                        }
+                    }
+                }
+            }
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c == '\n' {
+                    break;
+                }
+                chars.next();
+                match c {
+                    '\r' => {}
+                    c if quoted => {
+                        return Err(eyre::eyre!(
+                            "row {}: unexpected {:?} after a quoted cell",
+                            rows.len() + 1,
+                            c
+                        ))
+                    }
+                    c => cell.push(c),
+                }
+            }
+            row.push((cell, quoted));
+
+            if chars.next() != Some(',') {
+                break;
+            }
         }
 
-        passport
+        // blank lines are not rows
+        if row != [(String::new(), false)] {
+            rows.push(row);
+        }
+    }
+
+    Ok(rows)
+}
+
+/// A JSON document, with numbers kept as written.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(src: &str) -> eyre::Result<Self> {
+        let mut parser = JsonParser { src, pos: 0 };
+        let json = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < src.len() {
+            return Err(parser.error("unexpected input after the document"));
+        }
+
+        Ok(json)
+    }
+}
+
+struct JsonParser<'s> {
+    src: &'s str,
+    /// Byte offset of the next char.
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, msg: &str) -> eyre::Report {
+        let (line, column) = line_and_column(self.src, self.pos);
+        eyre::eyre!("line {}, column {}: {}", line, column, msg)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> eyre::Result<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {:?}", c)))
+        }
+    }
+
+    fn value(&mut self) -> eyre::Result<Json> {
+        self.skip_whitespace();
+        let rest = &self.src[self.pos..];
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if !self.eat('}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(':')?;
+                        fields.push((key, self.value()?));
+                        if self.eat('}') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut values = vec![];
+                if !self.eat(']') {
+                    loop {
+                        values.push(self.value()?);
+                        if self.eat(']') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                Ok(Json::Array(values))
+            }
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit() && !"+-.eE".contains(c))
+                    .unwrap_or(rest.len());
+                if rest[..len].parse::<f64>().is_err() {
+                    return Err(self.error("invalid number"));
+                }
+                self.pos += len;
+                Ok(Json::Number(rest[..len].to_owned()))
+            }
+            _ => {
+                for (word, json) in [
+                    ("null", Json::Null),
+                    ("true", Json::Bool(true)),
+                    ("false", Json::Bool(false)),
+                ] {
+                    if rest.starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(json);
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+
+    fn string(&mut self) -> eyre::Result<String> {
+        if self.peek() != Some('"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+
+        let mut out = String::new();
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escape = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += escape.len_utf8();
+                    out.push(match escape {
+                        '"' | '\\' | '/' => escape,
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'u' => {
+                            let code = self
+                                .src
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid \\u escape"))?;
+                            self.pos += 4;
+                            code
+                        }
+                        _ => return Err(self.error("unknown escape")),
+                    });
+                }
+                c if c.is_control() => return Err(self.error("control character in string")),
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+/// Raw field values by key, so that a [`Schema`] can check any kind of passport.
+trait Fields {
+    fn field(&self, key: &str) -> Option<&str>;
+}
+
+/// The rules of the second part, in the format read by [`Schema::parse`].
+const STRICT_SCHEMA: &str = "\
+byr: year 1920..=2002
+iyr: year 2010..=2020
+eyr: year 2020..=2030
+hgt: number cm 150..=193, in 59..=76
+hcl: colour
+ecl: enum amb blu brn gry grn hzl oth
+pid: digits 9
+cid?: text
+";
+
+/// What a field value must look like.
+#[derive(Debug, PartialEq)]
+enum FieldType {
+    /// Four digits within the range.
+    Year(std::ops::RangeInclusive<u32>),
+    /// A number directly followed by one of the units, within the range of that unit.
+    /// The unit may be empty.
+    Number(Vec<(String, std::ops::RangeInclusive<u32>)>),
+    /// `#` followed by six hexadecimal digits.
+    Colour,
+    Enum(Vec<String>),
+    /// Exactly this many digits.
+    Digits(usize),
+    Text,
+}
+
+impl FieldType {
+    fn check(&self, value: &str) -> bool {
+        let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+
+        match self {
+            FieldType::Year(range) => {
+                value.len() == 4 && digits(value) && range.contains(&value.parse().unwrap())
+            }
+            FieldType::Number(units) => units.iter().any(|(unit, range)| {
+                matches!(value.strip_suffix(unit.as_str()), Some(n) if digits(n)
+                    && n.parse::<u32>().is_ok_and(|n| range.contains(&n)))
+            }),
+            FieldType::Colour => {
+                value.len() == 7
+                    && value.starts_with('#')
+                    && value[1..].chars().all(|c| c.is_ascii_hexdigit())
+            }
+            FieldType::Enum(values) => values.iter().any(|v| v == value),
+            FieldType::Digits(len) => value.len() == *len && digits(value),
+            FieldType::Text => true,
+        }
+    }
+}
+
+impl std::str::FromStr for FieldType {
+    type Err = eyre::Report;
+
+    /// Parses a type name followed by its constraints, as in `year 1920..=2002`,
+    /// `number cm 150..=193, in 59..=76`, `colour`, `enum amb blu`, `digits 9` or `text`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let range = |s: &str| -> eyre::Result<std::ops::RangeInclusive<u32>> {
+            let (start, end) = s
+                .split_once("..=")
+                .ok_or_else(|| eyre::eyre!("expected `min..=max`, found {:?}", s))?;
+            Ok(start.trim().parse()?..=end.trim().parse()?)
+        };
+
+        let (name, args) = s.split_once(' ').unwrap_or((s, ""));
+        let args = args.trim();
+        match name {
+            "year" => Ok(FieldType::Year(range(args)?)),
+            "number" => {
+                let units = args
+                    .split(',')
+                    .map(|unit| match unit.trim().split_once(' ') {
+                        Some((unit, bounds)) => Ok((unit.to_owned(), range(bounds)?)),
+                        None => Ok((String::new(), range(unit)?)),
+                    })
+                    .collect::<eyre::Result<_>>()?;
+                Ok(FieldType::Number(units))
+            }
+            "colour" if args.is_empty() => Ok(FieldType::Colour),
+            "enum" if !args.is_empty() => Ok(FieldType::Enum(
+                args.split_whitespace().map(str::to_owned).collect(),
+            )),
+            "digits" => Ok(FieldType::Digits(args.parse()?)),
+            "text" if args.is_empty() => Ok(FieldType::Text),
+            _ => Err(eyre::eyre!("unknown field type {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct FieldRule {
+    key: String,
+    kind: FieldType,
+    required: bool,
+}
+
+/// The fields a passport must have and the values they accept.
+#[derive(Debug, PartialEq)]
+struct Schema(Vec<FieldRule>);
+
+impl Schema {
+    /// Parses one `key: type constraints` rule per line, with `key?:` for an optional
+    /// field. Blank lines and `#` comments are skipped.
+    fn parse(src: &str) -> eyre::Result<Self> {
+        let mut rules = vec![];
+        for (idx, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let rule = line
+                .split_once(':')
+                .ok_or_else(|| eyre::eyre!("expected `key: type`"))
+                .and_then(|(key, kind)| {
+                    let (key, required) = match key.trim().strip_suffix('?') {
+                        Some(key) => (key, false),
+                        None => (key.trim(), true),
+                    };
+                    Ok(FieldRule {
+                        key: key.to_owned(),
+                        kind: kind.trim().parse()?,
+                        required,
+                    })
+                })
+                .map_err(|err| eyre::eyre!("line {}: {}", idx + 1, err))?;
+            rules.push(rule);
+        }
+
+        Ok(Schema(rules))
+    }
+
+    /// Whether every required field is there and every field there has a valid value.
+    fn check<F: Fields>(&self, passport: &F) -> bool {
+        self.0.iter().all(|rule| match passport.field(&rule.key) {
+            Some(value) => rule.kind.check(value),
+            None => !rule.required,
+        })
     }
 }
 
@@ -394,14 +1980,17 @@ mod test {
 
         let batch: Batch<Passport> = Batch::parse(data);
 
-        assert_eq!(2, batch.number_of_valid());
+        assert_eq!(
+            2,
+            batch.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+        );
     }
 
     macro_rules! assert_valid {
         ($expected:expr, $field:expr) => {
             assert_eq!(
                 $expected,
-                $field.is_valid(),
+                $field.validate().is_ok(),
                 "expected {:?} to be valid",
                 $field.value()
             );
@@ -414,6 +2003,27 @@ mod test {
         assert_valid!(false, BirthYear::parse("2003"));
     }
 
+    #[test]
+    fn derived_fields() {
+        assert_valid!(true, IssueYear::parse("2010"));
+        assert_valid!(false, IssueYear::parse("2021"));
+        assert_valid!(true, ExpirationYear::parse("2030"));
+        assert_valid!(false, ExpirationYear::parse("twenty"));
+
+        let raw = "cid:147 eyr:2020\nhcl:#fffffd pid:860033327";
+        let passport = StrictPassport::from(raw);
+        assert_eq!(Some(2020), passport.expiration_year.value());
+        assert_eq!(Some("#fffffd"), passport.hair_color.value());
+        // values are slices of the input, not copies
+        assert!(std::ptr::eq(
+            &raw[21..28],
+            passport.hair_color.value().unwrap()
+        ));
+        assert_eq!("147", passport.country_id);
+        assert_eq!(None, passport.birth_year.value());
+        assert!(passport.passport_id.validate().is_ok());
+    }
+
     #[test]
     fn height() {
         assert_valid!(true, Height::parse("60in"));
@@ -459,7 +2069,406 @@ mod test {
 
         let batch: Batch<StrictPassport> = Batch::parse(data);
 
-        assert_eq!(0, batch.number_of_valid());
+        assert_eq!(
+            0,
+            batch.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn validation_reports() {
+        let data = "eyr:1972 cid:100\n\
+              hcl:#18171d ecl:amb hgt:170 pid:186cm iyr:2018 byr:1926\n\
+              \n\
+              iyr:2019\n\
+              hcl:#602927 eyr:1967 hgt:170cm\n\
+              ecl:grn pid:012533040 byr:1946\n\
+              \n\
+              hcl:dab227 iyr:2012\n\
+              ecl:brn hgt:182cm pid:021572410 eyr:2020 byr:1992 cid:277\n\
+              \n\
+              hgt:59cm ecl:zzz\n\
+              eyr:2038 hcl:74454a iyr:2023\n\
+              pid:3556412378 byr:2007";
+        let batch: Batch<StrictPassport> = Batch::parse(data);
+
+        let reports = batch.reports(RequiredFields::NORTH_POLE_CREDENTIALS);
+        assert_eq!(
+            "eyr: 1972 is not within 2020..=2030; hgt: cannot parse \"170\"; \
+             pid: \"186cm\" does not look like 9 digits",
+            reports[0].to_string()
+        );
+        assert_eq!(
+            (
+                "hgt",
+                FieldError::OutOfRange {
+                    value: 59,
+                    min: 150,
+                    max: 193,
+                    unit: "cm"
+                }
+            ),
+            reports[3].errors[3]
+        );
+        assert_eq!(7, reports[3].errors.len());
+
+        let reason = |key, kind| Reason { key, kind };
+        let failures = batch.common_failures(RequiredFields::NORTH_POLE_CREDENTIALS);
+        assert_eq!(
+            vec![
+                (reason("eyr", ErrorKind::OutOfRange), 3),
+                (reason("hcl", ErrorKind::BadFormat), 2),
+                (reason("pid", ErrorKind::BadFormat), 2),
+                (reason("byr", ErrorKind::OutOfRange), 1),
+            ],
+            failures[..4]
+        );
+        let groups = batch.invalid_by_reason(RequiredFields::NORTH_POLE_CREDENTIALS);
+        assert_eq!(vec![0, 1, 3], groups[&reason("eyr", ErrorKind::OutOfRange)]);
+        assert_eq!(vec![3], groups[&reason("ecl", ErrorKind::Unparsable)]);
+
+        let passport = Passport::from("byr:1990 hgt:150cm");
+        assert_eq!(
+            5,
+            passport
+                .validate(RequiredFields::NORTH_POLE_CREDENTIALS)
+                .errors
+                .len()
+        );
+        assert_eq!(
+            Some(&("iyr", FieldError::Missing)),
+            passport
+                .validate(RequiredFields::NORTH_POLE_CREDENTIALS)
+                .errors
+                .first()
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        let raw = "byr:1937 iyr:2017 nick:bob\nbyr:1940 stamp";
+        let options = |keys, duplicates| ParseOptions { keys, duplicates };
+
+        let passport = Passport::from(raw);
+        assert_eq!("1940", passport.byr);
+        assert_eq!(Some("bob"), passport.field("nick"));
+        assert_eq!(Some(&""), passport.extra.get("stamp"));
+
+        let first = options(Keys::Lenient, Duplicates::First);
+        let passport = StrictPassport::parse_with(raw, first).unwrap();
+        assert_eq!(Some(1937), passport.birth_year.value());
+        assert_eq!(2, passport.extra.len());
+
+        let err = Passport::parse_with(raw, options(Keys::Lenient, Duplicates::Error));
+        assert_eq!(
+            Err(ParseError {
+                offset: 27,
+                kind: ParseErrorKind::DuplicateKey("byr".to_owned())
+            }),
+            err.map(|_| ())
+        );
+        let err = StrictPassport::parse_with(raw, options(Keys::Strict, Duplicates::Last));
+        assert_eq!(
+            Some(ParseErrorKind::UnknownKey("nick".to_owned())),
+            err.err().map(|e| e.kind)
+        );
+
+        let batch = "byr:1937\n\nhgt:183cm\niyr:2017 eyr 2020\n";
+        let err =
+            Batch::<StrictPassport>::parse_with(batch, options(Keys::Strict, Duplicates::Error))
+                .err()
+                .unwrap();
+        assert_eq!(
+            "passport 2, line 4, column 10: expected `key:value`, found \"eyr\"",
+            err.to_string()
+        );
+        let batch = Batch::<StrictPassport>::parse_with(batch, ParseOptions::default()).unwrap();
+        assert_eq!(Some(&""), batch.0[1].extra.get("2020"));
+
+        // a known key without a value is set aside, not taken as the field
+        let raw = "byr:1937 byr";
+        let passport = Passport::from(raw);
+        assert_eq!("1937", passport.byr);
+        assert_eq!(Some(&""), passport.extra.get("byr"));
+        for duplicates in [Duplicates::First, Duplicates::Error] {
+            let passport = Passport::parse_with(raw, options(Keys::Lenient, duplicates)).unwrap();
+            assert_eq!("1937", passport.byr);
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        let data = "\r\n\
+            ecl:gry pid:860033327 eyr:2020 hcl:#fffffd  \r\n\
+            byr:1937 iyr:2017 cid:147 hgt:183cm\r\n\
+            \r\n\
+            \t\n\
+            \n\
+            iyr:2013 ecl:amb cid:350 eyr:2023 pid:028048884\n\
+            hcl:#cfa07d byr:1929\n\
+            \n\
+            hcl:#ae17e1 iyr:2013 eyr:2024 ecl:brn pid:760753108 byr:1931 hgt:179cm \n\
+            \n\n";
+
+        let batch: Vec<&str> = records(data).collect();
+        assert_eq!(3, batch.len());
+        assert_eq!(
+            "ecl:gry pid:860033327 eyr:2020 hcl:#fffffd  \r\nbyr:1937 iyr:2017 cid:147 hgt:183cm",
+            batch[0]
+        );
+        let batch: Batch<Passport> = Batch::parse(data);
+        assert_eq!(
+            2,
+            batch.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+        );
+        assert_eq!("183cm", batch.0[0].hgt);
+
+        let mut reader = RecordReader::new(data.as_bytes());
+        assert_eq!(
+            Some((
+                2,
+                "ecl:gry pid:860033327 eyr:2020 hcl:#fffffd\nbyr:1937 iyr:2017 cid:147 hgt:183cm"
+            )),
+            reader.next_record().unwrap()
+        );
+        assert_eq!(7, reader.next_record().unwrap().unwrap().0);
+        assert_eq!(10, reader.next_record().unwrap().unwrap().0);
+        assert_eq!(None, reader.next_record().unwrap());
+
+        let counts = count_valid_streaming(
+            data.as_bytes(),
+            &[Strategy::LENIENT, Strategy::STRICT],
+            RequiredFields::NORTH_POLE_CREDENTIALS,
+        )
+        .unwrap();
+        assert_eq!(vec![2, 2], counts);
+    }
+
+    #[test]
+    fn required_fields() {
+        let data =
+            "ecl:gry pid:860033327 eyr:2020 hcl:#fffffd byr:1937 iyr:2017 cid:147 hgt:183cm\n\
+              \n\
+              hcl:#ae17e1 iyr:2013 eyr:2024 ecl:brn pid:760753108 byr:1931 hgt:179cm\n\
+              \n\
+              byr:1990 hgt:150cm";
+        let batch: Batch<Passport> = Batch::parse(data);
+        let strict: Batch<StrictPassport> = Batch::parse(data);
+
+        // one parsed batch, several policies
+        let custom: RequiredFields = "byr, hgt".parse().unwrap();
+        assert_eq!(
+            2,
+            batch.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+        );
+        assert_eq!(1, batch.number_of_valid(RequiredFields::FULL_PASSPORT));
+        assert_eq!(3, batch.number_of_valid(custom));
+        assert_eq!(1, strict.number_of_valid(RequiredFields::FULL_PASSPORT));
+        assert_eq!(3, strict.number_of_valid(custom));
+        assert_eq!(
+            Some(&("cid", FieldError::Missing)),
+            strict.reports(RequiredFields::FULL_PASSPORT)[1]
+                .errors
+                .first()
+        );
+
+        assert_eq!(
+            Ok(RequiredFields::FULL_PASSPORT),
+            "full".parse().map_err(|_| ())
+        );
+        assert_eq!("passports with byr, hgt", custom.to_string());
+        assert_eq!(
+            "North Pole credentials",
+            RequiredFields::NORTH_POLE_CREDENTIALS.to_string()
+        );
+        let err = "byr,age".parse::<RequiredFields>().unwrap_err();
+        assert_eq!("unknown field \"age\"", err.to_string());
+    }
+
+    #[test]
+    fn cross_field_rules() {
+        let data = "byr:1990 iyr:2012 eyr:2022 hgt:183cm hcl:#fffffd ecl:gry pid:860033327\n\
+              \n\
+              byr:2000 iyr:2012 eyr:2030 hgt:183cm hcl:#fffffd ecl:gry pid:860033327\n\
+              \n\
+              byr:2003 iyr:2012 eyr:2011 hgt:183cm hcl:#fffffd ecl:gry pid:860033327";
+
+        let strict: Batch<StrictPassport> = Batch::parse(data);
+        assert_eq!(
+            2,
+            strict.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+        );
+        let checked: Batch<Checked<StrictPassport>> = Batch::parse(data);
+        assert_eq!(
+            1,
+            checked.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+        );
+
+        let reports = checked.reports(RequiredFields::NORTH_POLE_CREDENTIALS);
+        assert_eq!(
+            "age at issue: `issue_year - birth_year >= 16` does not hold for \
+             birth_year = 2000, issue_year = 2012; \
+             validity: `expiration_year - issue_year <= 10` does not hold for \
+             issue_year = 2012, expiration_year = 2030",
+            reports[1].to_string()
+        );
+        // rules are only checked on valid fields: byr is out of range
+        let reasons: Vec<_> = reports[2].reasons().map(|r| r.to_string()).collect();
+        assert_eq!(vec!["byr out of range", "eyr out of range"], reasons);
+
+        // the examples of the puzzle are too generous with the validity
+        let data = "iyr:2010 hgt:158cm hcl:#b6652a ecl:blu byr:1944 eyr:2021 pid:093154719";
+        let passport = Checked::<StrictPassport>::from(data);
+        assert_eq!(
+            Some(Reason {
+                key: "validity",
+                kind: ErrorKind::Violated
+            }),
+            passport
+                .validate(RequiredFields::NORTH_POLE_CREDENTIALS)
+                .reasons()
+                .next()
+        );
+    }
+
+    #[test]
+    fn strategies() {
+        let data = "eyr:1972 cid:100\n\
+              hcl:#18171d ecl:amb hgt:170 pid:186cm iyr:2018 byr:1926\n\
+              \n\
+              pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980 hcl:#623a2f\n\
+              \n\
+              byr:1990 iyr:2012 eyr:2022 hgt:183cm hcl:#fffffd ecl:gry pid:860033327\n\
+              \n\
+              iyr:2013 ecl:amb cid:350 eyr:2023 pid:028048884 hcl:#cfa07d byr:1929";
+        let batch: Batch<Passport> = Batch::parse(data);
+        let required = RequiredFields::NORTH_POLE_CREDENTIALS;
+
+        // the same verdicts as parsing each kind of passport
+        let counts: Vec<_> = [Strategy::LENIENT, Strategy::STRICT, Strategy::CHECKED]
+            .iter()
+            .map(|&strategy| batch.view(strategy).number_of_valid(required))
+            .collect();
+        assert_eq!(vec![3, 2, 1], counts);
+        let strict: Batch<StrictPassport> = Batch::parse(data);
+        assert_eq!(
+            strict.reports(required),
+            batch.view(Strategy::STRICT).reports(required)
+        );
+
+        // an empty value is missing both ways, so only a required key makes it fail
+        let data = "byr:1990 hgt:150cm iyr:";
+        let batch_empty: Batch<Passport> = Batch::parse(data);
+        let strict_empty: Batch<StrictPassport> = Batch::parse(data);
+        for required in ["byr,hgt", "byr,hgt,iyr"] {
+            let required: RequiredFields = required.parse().unwrap();
+            assert_eq!(
+                strict_empty.reports(required),
+                batch_empty.view(Strategy::STRICT).reports(required)
+            );
+        }
+        let required_iyr: RequiredFields = "byr,hgt,iyr".parse().unwrap();
+        assert_eq!(1, strict_empty.number_of_valid("byr,hgt".parse().unwrap()));
+        assert_eq!(
+            "iyr: missing",
+            strict_empty.0[0].validate(required_iyr).to_string()
+        );
+
+        let disagreements = batch.disagreements(Strategy::LENIENT, Strategy::STRICT, required);
+        assert_eq!(1, disagreements.len());
+        let (idx, lenient, strict) = &disagreements[0];
+        assert_eq!(0, *idx);
+        assert_eq!("valid", lenient.to_string());
+        assert_eq!(3, strict.errors.len());
+
+        let disagreements = batch.disagreements(
+            "strict".parse().unwrap(),
+            "checked".parse().unwrap(),
+            required,
+        );
+        let indices: Vec<_> = disagreements.iter().map(|(idx, _, _)| *idx).collect();
+        assert_eq!(vec![1], indices);
+        let err = "lax".parse::<Strategy>().err().unwrap();
+        assert_eq!("unknown strategy \"lax\"", err.to_string());
+    }
+
+    #[test]
+    fn export() {
+        let raw =
+            "ecl:gry pid:860033327 eyr:2020 hcl:#fffffd byr:1937 iyr:2017 cid:147 hgt:183cm\n\
+                   \n\
+                   hgt:59 ecl:zzz note:\"a,b\" stamp";
+        let batch: Batch<StrictPassport> = Batch::parse(raw);
+        let write = |format| {
+            let mut out = vec![];
+            batch.write(format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            "byr:1937 iyr:2017 eyr:2020 hgt:183cm hcl:#fffffd ecl:gry pid:860033327 cid:147\n\
+             \n\
+             hgt:59 ecl:zzz note:\"a,b\" stamp\n",
+            write(Format::Pairs)
+        );
+        let mut out = vec![];
+        Batch::<Passport>::parse("byr:1937 byr")
+            .write(Format::Pairs, &mut out)
+            .unwrap();
+        assert_eq!("byr:1937 byr\n", String::from_utf8(out).unwrap());
+        let json = write(Format::Json);
+        assert_eq!(
+            "[\n  {\"byr\": 1937, \"iyr\": 2017, \"eyr\": 2020, \
+             \"hgt\": {\"value\": 183, \"unit\": \"cm\"}, \"hcl\": \"#fffffd\", \
+             \"ecl\": \"gry\", \"pid\": \"860033327\", \"cid\": \"147\"},\n  \
+             {\"hgt\": \"59\", \"ecl\": \"zzz\", \"note\": \"\\\"a,b\\\"\", \"stamp\": \"\"}\n]\n",
+            json
+        );
+        let csv = write(Format::Csv);
+        assert_eq!(
+            "byr,iyr,eyr,hgt,hcl,ecl,pid,cid,note,stamp\n\
+             1937,2017,2020,183cm,#fffffd,gry,860033327,147,,\n\
+             ,,,59,,zzz,,,\"\"\"a,b\"\"\",\"\"\n",
+            csv
+        );
+
+        for (format, src) in [(Format::Json, &json), (Format::Csv, &csv)] {
+            let raw = import(src, format).unwrap();
+            let imported: Batch<StrictPassport> = Batch::parse(&raw);
+            assert_eq!(batch.0, imported.0, "{:?}", format);
+        }
+
+        let err = import("[{\"byr\": 1937}, {\"hgt\": true}]", Format::Json).unwrap_err();
+        assert_eq!("passport 2: unsupported value for \"hgt\"", err.to_string());
+        let err = import("[{\"note\": \"two words\"}]", Format::Json).unwrap_err();
+        assert_eq!(
+            "passport 1: \"note\": \"two words\" cannot be written as `key:value`",
+            err.to_string()
+        );
+        let err = import("byr,hgt\n1937\n", Format::Csv).unwrap_err();
+        assert_eq!("row 2: expected 2 cells, found 1", err.to_string());
+    }
+
+    #[test]
+    fn write_round_trips() {
+        fn write<P: Export>(batch: &Batch<P>, format: Format) -> String {
+            let mut out = vec![];
+            batch.write(format, &mut out).unwrap();
+            import(&String::from_utf8(out).unwrap(), format).unwrap()
+        }
+
+        for seed in 1..=20 {
+            let raw = generate(50, seed);
+            let passports: Batch<Passport> = Batch::parse(&raw);
+            let strict: Batch<StrictPassport> = Batch::parse(&raw);
+
+            for format in [Format::Pairs, Format::Json, Format::Csv] {
+                let written = write(&passports, format);
+                assert_eq!(passports.0, Batch::<Passport>::parse(&written).0);
+                let written = write(&strict, format);
+                assert_eq!(strict.0, Batch::<StrictPassport>::parse(&written).0);
+            }
+        }
     }
 
     #[test]
@@ -479,6 +2488,50 @@ mod test {
 
         let batch: Batch<StrictPassport> = Batch::parse(data);
 
-        assert_eq!(5, batch.number_of_valid());
+        assert_eq!(
+            4,
+            batch.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn schema() {
+        let data = "pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980 hcl:#623a2f\n\
+            \n\
+            hgt:59cm ecl:zzz eyr:2038 hcl:74454a iyr:2023 pid:3556412378 byr:2007\n\
+            \n\
+            iyr:2013 ecl:amb cid:350 eyr:2023 pid:028048884 hcl:#cfa07d byr:1929";
+
+        // the built-in schema agrees with the hand-written fields
+        let schema = Schema::parse(STRICT_SCHEMA).unwrap();
+        let passports: Batch<Passport> = Batch::parse(data);
+        assert_eq!(1, passports.number_conforming(&schema));
+        let strict: Batch<StrictPassport> = Batch::parse(data);
+        assert_eq!(
+            1,
+            strict.number_of_valid(RequiredFields::NORTH_POLE_CREDENTIALS)
+        );
+
+        // rules are data, so they can change without touching the code
+        let schema = Schema::parse(
+            "# only some fields matter\n\
+             byr: year 1900..=2010\n\
+             hgt: number cm 50..=200, in 59..=76\n\
+             ecl?: enum amb grn zzz\n",
+        )
+        .unwrap();
+        assert_eq!(2, passports.number_conforming(&schema));
+
+        let rule = |s: &str| s.parse::<FieldType>().unwrap();
+        assert!(rule("number 1..=10").check("7"));
+        assert!(!rule("number 1..=10").check("7cm"));
+        assert!(!rule("year 1920..=2002").check("+1990"));
+        assert!(rule("digits 3").check("007"));
+        assert!(!rule("colour").check("#12345g"));
+
+        let err = Schema::parse("byr: year 1920..=2002\nhgt number\n").unwrap_err();
+        assert_eq!("line 2: expected `key: type`", err.to_string());
+        let err = Schema::parse("byr: date\n").unwrap_err();
+        assert_eq!("line 1: unknown field type \"date\"", err.to_string());
     }
 }